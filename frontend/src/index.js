@@ -1,85 +1,6 @@
 import Vue from "vue";
 import InfiniteLoading from "vue-infinite-loading";
 
-import hljs from "highlight.js/lib/core";
-import properties from "highlight.js/lib/languages/properties.js"
-import apache from "highlight.js/lib/languages/apache.js"
-import bash from "highlight.js/lib/languages/bash.js"
-import clike from "highlight.js/lib/languages/c-like.js"
-import c from "highlight.js/lib/languages/c.js"
-import cpp from "highlight.js/lib/languages/cpp.js"
-import csharp from "highlight.js/lib/languages/cpp.js"
-import css from "highlight.js/lib/languages/css.js"
-import coffeescript from "highlight.js/lib/languages/coffeescript.js"
-import diff from "highlight.js/lib/languages/go.js"
-import go from "highlight.js/lib/languages/go.js"
-import xml from "highlight.js/lib/languages/xml.js"
-import http from "highlight.js/lib/languages/http.js"
-import json from "highlight.js/lib/languages/json.js"
-import java from "highlight.js/lib/languages/java.js"
-import javascript from "highlight.js/lib/languages/kotlin.js"
-import kotlin from "highlight.js/lib/languages/kotlin.js"
-import less from "highlight.js/lib/languages/less.js"
-import lua from "highlight.js/lib/languages/markdown.js"
-import makefile from "highlight.js/lib/languages/markdown.js"
-import markdown from "highlight.js/lib/languages/markdown.js"
-import nginx from "highlight.js/lib/languages/nginx.js"
-import objectivec from "highlight.js/lib/languages/objectivec.js"
-import php from "highlight.js/lib/languages/perl.js"
-import phptemplate from "highlight.js/lib/languages/perl.js"
-import perl from "highlight.js/lib/languages/perl.js"
-import plaintext from "highlight.js/lib/languages/plaintext.js"
-import python from "highlight.js/lib/languages/python.js"
-import ruby from "highlight.js/lib/languages/ruby.js"
-import rust from "highlight.js/lib/languages/rust.js"
-import scss from "highlight.js/lib/languages/sql.js"
-import sql from "highlight.js/lib/languages/sql.js"
-import shell from "highlight.js/lib/languages/shell.js"
-import swift from "highlight.js/lib/languages/swift.js"
-import ini from "highlight.js/lib/languages/ini.js"
-import typescript from "highlight.js/lib/languages/typescript.js"
-import yaml from "highlight.js/lib/languages/yaml.js"
-
-hljs.registerLanguage("properties", properties);
-hljs.registerLanguage("apache", apache);
-hljs.registerLanguage("bash", bash);
-hljs.registerLanguage("c-like", clike);
-hljs.registerLanguage("c", c);
-hljs.registerLanguage("cpp", cpp);
-hljs.registerLanguage("csharp", csharp);
-hljs.registerLanguage("css", css);
-hljs.registerLanguage("coffeescript", coffeescript);
-hljs.registerLanguage("diff", diff);
-hljs.registerLanguage("go", go);
-hljs.registerLanguage("xml", xml);
-hljs.registerAliases(["html"], {languageName: "xml"});
-hljs.registerLanguage("http", http);
-hljs.registerLanguage("json", json);
-hljs.registerLanguage("java", java);
-hljs.registerLanguage("javascript", javascript);
-hljs.registerLanguage("kotlin", kotlin);
-hljs.registerLanguage("less", less);
-hljs.registerLanguage("lua", lua);
-hljs.registerLanguage("makefile", makefile);
-hljs.registerLanguage("markdown", markdown);
-hljs.registerLanguage("nginx", nginx);
-hljs.registerLanguage("objectivec", objectivec);
-hljs.registerLanguage("php", php);
-hljs.registerLanguage("php-template", phptemplate);
-hljs.registerLanguage("perl", perl);
-hljs.registerLanguage("plaintext", plaintext);
-hljs.registerLanguage("python", python);
-hljs.registerLanguage("ruby", ruby);
-hljs.registerLanguage("rust", rust);
-hljs.registerLanguage("scss", scss);
-hljs.registerLanguage("sql", sql);
-hljs.registerLanguage("shell", shell);
-hljs.registerLanguage("swift", swift);
-hljs.registerLanguage("ini", ini);
-hljs.registerAliases(["toml"], {languageName: "ini"});
-hljs.registerLanguage("typescript", typescript);
-hljs.registerLanguage("yaml", yaml);
-
 Vue.component("pastes", {
 	components: {
 		InfiniteLoading,
@@ -91,6 +12,9 @@ Vue.component("pastes", {
 		return {
 			title_input: "",
 			content_input: "",
+			expires_input: "",
+			max_views_input: "",
+			password_input: "",
 
 			pastes: [],
 			page: 0,
@@ -129,11 +53,17 @@ Vue.component("pastes", {
 				}
 				this.title_input = "";
 				this.content_input = "";
+				this.expires_input = "";
+				this.max_views_input = "";
+				this.password_input = "";
 			};
 			xhr.setRequestHeader("content-type", "application/json");
 			xhr.send(JSON.stringify({
 				filename: this.title_input,
 				content: this.content_input,
+				expires_in: this.expires_input || null,
+				max_views: parseInt(this.max_views_input) || null,
+				password: this.password_input || null,
 			}));
 		},
 		select: function (event) {
@@ -154,23 +84,15 @@ Vue.component("pastes", {
 		},
 	},
 
-	watch: {
-		pastes: function (_value) {
-			this.pastes.forEach(paste => {
-				if (!paste.rendered) {
-					console.log("rendering id: " + paste.id);
-					paste.rendered = hljs.highlightAuto(paste.content).value;
-				}
-			})
-		},
-	},
-
 	template: `
 <div class="full_height_flex_container">
 	<div class="magic">
 		<div id="pastes">
 			<section id="paste_form">
 				<label>Title: </label><input type="text" v-model="title_input">
+				<label>Expires in: </label><input type="text" placeholder="never, or e.g. 10m, 1h, 7d" v-model="expires_input">
+				<label>Max views: </label><input type="text" placeholder="unlimited" v-model="max_views_input">
+				<label>Password: </label><input type="password" placeholder="none" v-model="password_input">
 				<textarea 
 					name="content"
 					id="content_input"
@@ -182,10 +104,16 @@ Vue.component("pastes", {
 			</section>
 			<section v-for="paste in pastes" class="paste" v-on:dblclick="select">
 				<div class="paste_title_bar">
-					<a class="paste_title" :href="'/paste/' + paste.id">{{ paste.filename }}</a>
-					<a :href="'/raw/' + paste.id">[Raw]</a>
+					<a class="paste_title" :href="'/paste/' + paste.slug">{{ paste.filename }}</a>
+					<a :href="'/raw/' + paste.slug">[Raw]</a>
+				</div>
+				<div v-if="paste.expires_at || paste.max_views || paste.locked" class="paste_limits">
+					<span v-if="paste.locked">Locked, open it with the password</span>
+					<span v-if="paste.expires_at">Expires {{ new Date(paste.expires_at + "Z").toLocaleString() }}</span>
+					<span v-if="paste.max_views">Gone after {{ paste.max_views }} view(s), open it to read</span>
 				</div>
-				<div class="paste_content hljs">
+				<!-- Highlighted by the server. Locked pastes and pastes with a view limit only show their content when opened -->
+				<div class="paste_content">
 					<pre v-html="paste.rendered">
 					</pre>
 				</div>
@@ -212,6 +140,9 @@ Vue.component("connect-form", {
 	data: function () {
 		return {
 			nick: "",
+			room: localStorage.getItem("room") || "main",
+			// Never stored, the session remembers a claimed nick
+			secret: "",
 		};
 	},
 
@@ -220,7 +151,7 @@ Vue.component("connect-form", {
 		let color = localStorage.getItem("color");
 		if (nick !== null) {
 			this.nick = nick;
-			this.$emit("connect", {nick: this.nick, color: color || ""});
+			this.$emit("connect", {nick: this.nick, color: color || "", room: this.room, secret: this.secret});
 		}
 	},
 
@@ -233,7 +164,23 @@ Vue.component("connect-form", {
 		placeholder="Nick"
 		v-model="nick"
 		class="chat_input"
-		v-on:keyup.enter="$emit('connect', {nick, color: ''})">
+		v-on:keyup.enter="$emit('connect', {nick, color: '', room, secret})">
+	<input 
+		type="text"
+		name="room"
+		id="room"
+		placeholder="Room"
+		v-model="room"
+		class="chat_input"
+		v-on:keyup.enter="$emit('connect', {nick, color: '', room, secret})">
+	<input 
+		type="password"
+		name="secret"
+		id="secret"
+		placeholder="Secret (reserved nicks only)"
+		v-model="secret"
+		class="chat_input"
+		v-on:keyup.enter="$emit('connect', {nick, color: '', room, secret})">
 </div>`,
 });
 
@@ -241,17 +188,33 @@ const MsgType = {
 	Connected: "Connected",
 	Ping: "Ping",
 	Message: "Message",
+	MessageEdited: "MessageEdited",
+	MessageDeleted: "MessageDeleted",
+	ReactionUpdated: "ReactionUpdated",
+	Mention: "Mention",
+	Typing: "Typing",
+	RateLimited: "RateLimited",
+	Kicked: "Kicked",
+	Resync: "Resync",
+	PrivateMessage: "PrivateMessage",
 	Paste: "Paste",
 	NickChange: "NickChange",
 	ColorChange: "ColorChange",
+	UserJoined: "UserJoined",
+	UserLeft: "UserLeft",
+	UserRenamed: "UserRenamed",
+	Error: "Error",
 };
 
 Vue.component("chat", {
-	props: ["user", "messages"],
+	props: ["user", "messages", "online", "mentions", "typing"],
 
 	data: function () {
 		return {
 			user_msg: "",
+			reply_to: null,
+			thread: null,
+			last_typing: 0,
 		};
 	},
 
@@ -274,6 +237,13 @@ Vue.component("chat", {
 			}
 		},
 		send_msg: function () {
+			if (this.$root.socket) {
+				this.$root.socket.send(JSON.stringify({Msg: this.new_msg()}));
+				this.user_msg = "";
+				this.reply_to = null;
+				return;
+			}
+
 			let xhr = new XMLHttpRequest();
 			xhr.open("POST", "/send_msg", true);
 			xhr.onload = () => {
@@ -282,27 +252,77 @@ Vue.component("chat", {
 					return;
 				}
 				this.user_msg = "";
+				this.reply_to = null;
 			};
 			xhr.setRequestHeader("content-type", "application/json");
-			xhr.send(JSON.stringify(this.user_msg));
+			xhr.send(JSON.stringify(this.new_msg()));
 		},
-		send_cmd: function () {
+		on_input: function () {
+			if (this.user_msg.startsWith("/") || Date.now() - this.last_typing < 2000) {
+				return;
+			}
+			this.last_typing = Date.now();
+
+			if (this.$root.socket) {
+				this.$root.socket.send(JSON.stringify({Typing: {room: this.user.room}}));
+				return;
+			}
+
 			let xhr = new XMLHttpRequest();
-			xhr.open("POST", "/send_cmd", true);
-			xhr.onload = () => {
-				this.user_msg = "";
-				if (xhr.status !== 200) {
+			xhr.open("POST", "/typing", true);
+			xhr.setRequestHeader("content-type", "application/json");
+			xhr.send(JSON.stringify({room: this.user.room}));
+		},
+		new_msg: function () {
+			return {room: this.user.room, msg: this.user_msg, reply_to: this.reply_to && this.reply_to.id};
+		},
+		show_thread: function (msg) {
+			let req = new XMLHttpRequest();
+			req.open("GET", `/messages/${msg.reply_to || msg.id}/thread`, true);
+			req.onload = () => {
+				if (req.status !== 200) {
 					console.log("request failed");
+					return;
 				}
+				this.thread = JSON.parse(req.responseText);
 			};
-			xhr.setRequestHeader("content-type", "application/json");
-
+			req.send();
+		},
+		send_cmd: function () {
 			let msg_split = this.user_msg.split(" ");
 			let cmd = msg_split[0].charAt(1).toUpperCase() + msg_split[0].slice(2);
 
 			let payload = {};
 			payload[cmd] = msg_split.slice(1).join(" ");
 
+			// Claiming a reserved nick has to go through HTTP, which can store it in the session
+			if (cmd === "Nick" && msg_split.length > 2) {
+				this.post_cmd_http(payload);
+			} else {
+				this.post_cmd(payload);
+			}
+			this.user_msg = "";
+		},
+		react: function (msg, emoji) {
+			this.post_cmd({React: `${msg.id} ${emoji}`});
+		},
+		post_cmd: function (payload) {
+			if (this.$root.socket) {
+				this.$root.socket.send(JSON.stringify({Cmd: payload}));
+				return;
+			}
+			this.post_cmd_http(payload);
+		},
+		post_cmd_http: function (payload) {
+			let xhr = new XMLHttpRequest();
+			xhr.open("POST", "/send_cmd", true);
+			xhr.onload = () => {
+				if (xhr.status !== 200) {
+					console.log("request failed");
+				}
+			};
+			xhr.setRequestHeader("content-type", "application/json");
+
 			let payload_json = JSON.stringify(payload);
 			console.log(`Payload: ${payload_json}`);
 
@@ -324,20 +344,38 @@ Vue.component("chat", {
 <div>
 	<section class="full_height_flex_container" ref="messages">
 		<div class="magic">
-			<div class="message" v-for="msg in messages">
-				<span>[{{ msg.time | time }}] </span><span v-bind:style="{ color: msg.custom_nick_color || 'var(--default-nick-color)' }">{{ msg.nick }}</span>: <span class="msg_content">{{ msg.msg }}</span>
+			<div :class="['message', { private: msg.private, mention: mentions.includes(msg.id) }]" v-for="msg in messages">
+				<span>[{{ msg.time | time }}] </span><span v-bind:style="{ color: msg.custom_nick_color || 'var(--default-nick-color)' }">{{ msg.nick }}</span>: <span class="msg_content">{{ msg.msg }}</span><span v-if="msg.edited_at" class="edited"> (edited)</span>
+				<span class="reaction" v-for="(count, emoji) in msg.reactions" v-on:click="react(msg, emoji)">{{ emoji }} {{ count }}</span>
+				<span v-if="msg.reply_to" class="thread_link" v-on:click="show_thread(msg)">↪ thread</span>
+				<span v-else-if="msg.replies" class="thread_link" v-on:click="show_thread(msg)">{{ msg.replies }} replies</span>
+				<span v-if="!msg.private" class="thread_link" v-on:click="reply_to = msg">reply</span>
 			</div>
 		</div>
 	</section>
+	<section id="thread" v-if="thread">
+		<span class="thread_link" v-on:click="thread = null">close thread</span>
+		<div class="message" v-for="msg in [thread.root].concat(thread.replies)">
+			<span>[{{ msg.time | time }}] </span><span v-bind:style="{ color: msg.custom_nick_color || 'var(--default-nick-color)' }">{{ msg.nick }}</span>: <span class="msg_content">{{ msg.msg }}</span>
+		</div>
+	</section>
+	<section id="online">
+		Online: <span v-for="(u, idx) in online" v-bind:style="{ color: u.color || 'var(--default-nick-color)' }">{{ u.nick }}<span v-if="idx < online.length - 1">, </span></span>
+	</section>
 	<section id="input">
 		<label for="msg_input" v-bind:style="{ color: user.color || 'var(--default-nick-color)' }">{{ user.nick }}</label>
+		<span v-if="reply_to" class="thread_link" v-on:click="reply_to = null">replying to {{ reply_to.nick }} ✕</span>
 		<input 
 			type="text"
 			v-model="user_msg"
 			id="msg_input"
 			class="chat_input"
+			v-on:input="on_input()"
 			v-on:keyup.enter="send()">
 	</section>
+	<section id="typing">
+		<span v-if="Object.keys(typing).length > 0">{{ Object.keys(typing).join(", ") }} typing…</span>
+	</section>
 </div>
 `,
 });
@@ -350,14 +388,20 @@ let onload = () => (new Vue({
 		tabs: ["chat", "pastes", "info"],
 
 		eventSource: null,
+		socket: null,
 		connected: false,
 		has_unread_msg: false,
 
 		user: {
 			nick: "",
 			color: "",
+			room: "main",
+			secret: "",
 		},
 		messages: [],
+		mentions: [],
+		typing: {},
+		online: [],
 		pastes: [],
 	},
 
@@ -368,6 +412,9 @@ let onload = () => (new Vue({
 					return {
 						user: this.user,
 						messages: this.messages,
+						online: this.online,
+						mentions: this.mentions,
+						typing: this.typing,
 					};
 				case "pastes":
 					return {
@@ -395,8 +442,41 @@ let onload = () => (new Vue({
 			this.connect();
 		},
 		connect: function () {
-			this.eventSource = new EventSource(encodeURI(
-				`/events?nick=${this.user.nick}&color=${this.user.color}`));
+			if (!this.user.secret) {
+				this.open_stream();
+				return;
+			}
+
+			// The secret is kept out of the stream URL, which ends up in logs and history
+			let xhr = new XMLHttpRequest();
+			xhr.open("POST", "/claim_nick", true);
+			xhr.onload = () => {
+				if (xhr.status !== 200) {
+					console.log("request failed");
+					return;
+				}
+				this.open_stream();
+			};
+			xhr.setRequestHeader("content-type", "application/json");
+			xhr.send(JSON.stringify({nick: this.user.nick, secret: this.user.secret}));
+		},
+		open_stream: function () {
+			let query = ["nick", "color", "room"]
+				.map(param => `${param}=${encodeURIComponent(this.user[param])}`)
+				.join("&");
+
+			// Opt-in WebSocket transport for networks where proxies buffer server-sent events
+			if (localStorage.getItem("transport") === "ws" && window.WebSocket) {
+				let protocol = location.protocol === "https:" ? "wss:" : "ws:";
+				this.socket = new WebSocket(`${protocol}//${location.host}/ws?${query}`);
+
+				this.socket.onopen = event => console.log(event);
+				this.socket.onerror = event => console.log(event);
+				this.socket.onmessage = this.handle_message;
+				return;
+			}
+
+			this.eventSource = new EventSource(`/events?${query}`);
 
 			this.eventSource.onopen = event => console.log(event);
 			this.eventSource.onerror = event => console.log(event);
@@ -410,9 +490,12 @@ let onload = () => (new Vue({
 				case MsgType.Connected:
 					console.log("Connected");
 
-					this.messages.push(...msg.data);
+					// Replaces what we had, as a reconnect that couldn't be resumed sends the history again
+					this.messages.splice(0, this.messages.length, ...msg.data);
 
 					localStorage.setItem("nick", this.user.nick);
+					localStorage.setItem("room", this.user.room);
+					localStorage.removeItem("secret");
 
 					let last_tab = localStorage.getItem("last_tab");
 					if (last_tab) {
@@ -420,12 +503,65 @@ let onload = () => (new Vue({
 					}
 
 					this.connected = true;
+					this.fetch_online();
 
 					break;
 				case MsgType.Ping:
 					break;
 				case MsgType.Message:
-					this.messages.push(msg.data);
+					if (msg.room !== this.user.room) {
+						break;
+					}
+					if (this.messages.some(m => m.id === msg.data.id)) {
+						// Already part of a refetched history
+						break;
+					}
+					this.messages.push(Object.assign({reactions: {}, replies: 0}, msg.data));
+					this.stop_typing(msg.data.nick);
+					this.messages
+						.filter(m => m.id === msg.data.reply_to)
+						.forEach(m => m.replies += 1);
+
+					break;
+				case MsgType.MessageEdited: {
+					let idx = this.messages.findIndex(m => m.id === msg.data.id);
+					if (idx !== -1) {
+						this.messages.splice(idx, 1, Object.assign({}, this.messages[idx], msg.data));
+					}
+					break;
+				}
+				case MsgType.ReactionUpdated:
+					this.messages
+						.filter(m => m.id === msg.data.message_id)
+						.forEach(m => m.reactions = msg.data.reactions);
+					break;
+				case MsgType.MessageDeleted:
+					this.messages = this.messages.filter(m => m.id !== msg.data.id);
+					break;
+				case MsgType.Typing:
+					if (msg.room !== this.user.room) {
+						break;
+					}
+					this.stop_typing(msg.data.nick);
+					this.$set(this.typing, msg.data.nick, setTimeout(
+						() => this.stop_typing(msg.data.nick),
+						msg.data.expires_in * 1000,
+					));
+					break;
+				case MsgType.Mention:
+					if (!this.mentions.includes(msg.data.id)) {
+						this.mentions.push(msg.data.id);
+					}
+					this.notify();
+					break;
+				case MsgType.PrivateMessage:
+					this.messages.push({
+						nick: `${msg.data.from} → ${msg.data.to}`,
+						custom_nick_color: msg.data.custom_nick_color,
+						msg: msg.data.msg,
+						time: msg.data.time,
+						private: true,
+					});
 					this.notify();
 
 					break;
@@ -442,11 +578,82 @@ let onload = () => (new Vue({
 					this.user.nick = msg.data;
 					localStorage.setItem("nick", this.user.nick);
 					break;
+				case MsgType.UserJoined:
+					if (!this.online.some(u => u.nick === msg.data.nick)) {
+						this.online.push(msg.data);
+					}
+					break;
+				case MsgType.UserLeft:
+					this.online = this.online.filter(u => u.nick !== msg.data.nick);
+					break;
+				case MsgType.UserRenamed:
+					this.online
+						.filter(u => u.nick === msg.data.old_nick)
+						.forEach(u => u.nick = msg.data.new_nick);
+					break;
+				case MsgType.Resync:
+					if (msg.room === this.user.room) {
+						this.fetch_history();
+					}
+					break;
+				case MsgType.Kicked:
+					// Keeps EventSource from reconnecting on its own
+					if (this.eventSource) {
+						this.eventSource.close();
+						this.eventSource = null;
+					}
+					if (this.socket) {
+						this.socket.close();
+						this.socket = null;
+					}
+					this.connected = false;
+					break;
+				case MsgType.RateLimited:
+					this.messages.push({
+						nick: "server",
+						msg: `You're sending too fast, try again in ${msg.data.retry_after}s`,
+						time: new Date().toISOString(),
+						private: true,
+					});
+					break;
+				case MsgType.Error:
+					console.log("Error: ", msg.data);
+					break;
 				default:
 					console.log("Unknown type: ", msg.type);
 					break;
 			}
 		},
+		stop_typing: function (nick) {
+			if (nick in this.typing) {
+				clearTimeout(this.typing[nick]);
+				this.$delete(this.typing, nick);
+			}
+		},
+		fetch_history: function () {
+			let req = new XMLHttpRequest();
+			req.open("GET", `/history?room=${encodeURIComponent(this.user.room)}`, true);
+			req.onload = () => {
+				if (req.status !== 200) {
+					console.log("request failed");
+					return;
+				}
+				this.messages.splice(0, this.messages.length, ...JSON.parse(req.responseText));
+			};
+			req.send();
+		},
+		fetch_online: function () {
+			let req = new XMLHttpRequest();
+			req.open("GET", `/users?room=${encodeURIComponent(this.user.room)}`, true);
+			req.onload = () => {
+				if (req.status !== 200) {
+					console.log("request failed");
+					return;
+				}
+				this.online = JSON.parse(req.responseText);
+			};
+			req.send();
+		},
 		notify: function () {
 			if (!document.hasFocus() && !this.has_unread_msg) {
 				this.has_unread_msg = true;
@@ -458,6 +665,11 @@ let onload = () => (new Vue({
 				this.has_unread_msg = false;
 				document.title = document.title.substring(2);
 			}
+			if (this.mentions.length > 0) {
+				let req = new XMLHttpRequest();
+				req.open("POST", "/mentions/read", true);
+				req.send();
+			}
 		},
 	},
 }));
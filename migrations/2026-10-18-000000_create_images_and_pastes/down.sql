@@ -0,0 +1,2 @@
+-- The tables predate the migrations on most databases, and keep their data
+SELECT 1;
@@ -0,0 +1,14 @@
+-- Tables chitchat started out with. Existing databases already have them.
+CREATE TABLE IF NOT EXISTS images (
+	id BIGSERIAL PRIMARY KEY,
+	filename VARCHAR NOT NULL,
+	creation_date TIMESTAMP NOT NULL,
+	content BYTEA NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS pastes (
+	id BIGSERIAL PRIMARY KEY,
+	filename VARCHAR,
+	content TEXT,
+	creation_date TIMESTAMP NOT NULL
+);
@@ -0,0 +1,11 @@
+CREATE TABLE messages (
+	id BIGSERIAL PRIMARY KEY,
+	room VARCHAR NOT NULL,
+	nick VARCHAR NOT NULL,
+	custom_nick_color VARCHAR,
+	msg TEXT NOT NULL,
+	time TIMESTAMPTZ NOT NULL
+);
+
+-- Room history is loaded newest first
+CREATE INDEX messages_room_id_idx ON messages (room, id);
@@ -0,0 +1,6 @@
+-- Nicks are stored lowercase
+CREATE TABLE reserved_nicks (
+	nick VARCHAR PRIMARY KEY,
+	secret_hash VARCHAR NOT NULL,
+	creation_date TIMESTAMP NOT NULL
+);
@@ -0,0 +1,4 @@
+ALTER TABLE messages
+	DROP COLUMN author_id,
+	DROP COLUMN edited_at,
+	DROP COLUMN deleted;
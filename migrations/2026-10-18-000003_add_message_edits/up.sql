@@ -0,0 +1,7 @@
+-- Messages from before authors were recorded can't be edited by anyone but moderators
+ALTER TABLE messages
+	ADD COLUMN author_id BIGINT NOT NULL DEFAULT 0,
+	ADD COLUMN edited_at TIMESTAMPTZ,
+	ADD COLUMN deleted BOOLEAN NOT NULL DEFAULT FALSE;
+
+ALTER TABLE messages ALTER COLUMN author_id DROP DEFAULT;
@@ -0,0 +1,6 @@
+CREATE TABLE reactions (
+	message_id BIGINT NOT NULL REFERENCES messages (id),
+	user_id BIGINT NOT NULL,
+	emoji VARCHAR NOT NULL,
+	PRIMARY KEY (message_id, user_id, emoji)
+);
@@ -0,0 +1,3 @@
+ALTER TABLE messages ADD COLUMN reply_to BIGINT REFERENCES messages (id);
+
+CREATE INDEX messages_reply_to_idx ON messages (reply_to);
@@ -0,0 +1,6 @@
+-- Unread mentions only, nicks are stored lowercase
+CREATE TABLE mentions (
+	nick VARCHAR NOT NULL,
+	message_id BIGINT NOT NULL REFERENCES messages (id),
+	PRIMARY KEY (nick, message_id)
+);
@@ -0,0 +1,10 @@
+-- Nicks are stored lowercase
+CREATE TABLE bans (
+	id BIGSERIAL PRIMARY KEY,
+	nick VARCHAR,
+	ip VARCHAR,
+	creation_date TIMESTAMP NOT NULL
+);
+
+CREATE INDEX bans_nick_idx ON bans (nick);
+CREATE INDEX bans_ip_idx ON bans (ip);
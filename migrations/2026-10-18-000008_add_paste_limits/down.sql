@@ -0,0 +1,4 @@
+ALTER TABLE pastes
+	DROP COLUMN expires_at,
+	DROP COLUMN max_views,
+	DROP COLUMN views;
@@ -0,0 +1,4 @@
+ALTER TABLE pastes
+	ADD COLUMN expires_at TIMESTAMP,
+	ADD COLUMN max_views INTEGER,
+	ADD COLUMN views INTEGER NOT NULL DEFAULT 0;
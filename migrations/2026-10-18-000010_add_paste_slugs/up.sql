@@ -0,0 +1,20 @@
+ALTER TABLE pastes ADD COLUMN slug VARCHAR;
+
+-- Existing pastes get random slugs of the default length. Referencing the row
+-- keeps the subquery from being evaluated only once.
+UPDATE pastes SET slug = (
+	SELECT string_agg(
+		substr(
+			'0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz',
+			floor(random() * 62)::INTEGER + 1,
+			1
+		),
+		''
+	)
+	FROM generate_series(1, 10)
+	WHERE pastes.id IS NOT NULL
+);
+
+ALTER TABLE pastes
+	ALTER COLUMN slug SET NOT NULL,
+	ADD CONSTRAINT pastes_slug_key UNIQUE (slug);
@@ -0,0 +1,7 @@
+-- What each instance of a cluster shares with the others: its connected users
+-- and active mutes, as JSON. Rows of instances that stopped updating are stale.
+CREATE TABLE presences (
+	node BIGINT PRIMARY KEY,
+	snapshot TEXT NOT NULL,
+	updated_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1,63 @@
+use std::net::IpAddr;
+
+use crate::error::ChatError;
+use crate::models::NewBan;
+use diesel::prelude::*;
+
+/// Makes sure neither `nick` nor `ip` are banned.
+pub fn check(conn: &PgConnection, nick: &str, ip: Option<IpAddr>) -> Result<(), ChatError> {
+	use crate::schema::bans::dsl;
+
+	let ip = ip.map(|ip| ip.to_string());
+	let banned = dsl::bans
+		.select(dsl::id)
+		.filter(dsl::nick.eq(nick.to_lowercase()).or(dsl::ip.eq(ip)))
+		.first::<i64>(conn)
+		.optional()
+		.map_err(|e| {
+			println!("Error loading bans: {}", e);
+			ChatError::Unavailable
+		})?;
+
+	match banned {
+		Some(_) => Err(ChatError::Forbidden(String::from("You are banned"))),
+		None => Ok(()),
+	}
+}
+
+pub fn ban(conn: &PgConnection, nick: &str, ip: Option<IpAddr>) -> Result<(), ChatError> {
+	use crate::schema::bans::dsl::bans;
+
+	let ban = NewBan {
+		nick: Some(nick.to_lowercase()),
+		ip: ip.map(|ip| ip.to_string()),
+		creation_date: crate::handlers::now(),
+	};
+	diesel::insert_into(bans)
+		.values(&ban)
+		.execute(conn)
+		.map(|_| ())
+		.map_err(|e| {
+			println!("Error saving ban: {}", e);
+			ChatError::Unavailable
+		})
+}
+
+/// Lifts every ban of `target`, which is either a nick or an IP.
+pub fn unban(conn: &PgConnection, target: &str) -> Result<(), ChatError> {
+	use crate::schema::bans::dsl;
+
+	let lifted = diesel::delete(
+		dsl::bans.filter(dsl::nick.eq(target.to_lowercase()).or(dsl::ip.eq(target))),
+	)
+	.execute(conn)
+	.map_err(|e| {
+		println!("Error lifting ban: {}", e);
+		ChatError::Unavailable
+	})?;
+
+	match lifted {
+		0 => Err(ChatError::NotFound),
+		_ => Ok(()),
+	}
+}
@@ -1,148 +1,750 @@
-use std::sync::Mutex;
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
 use std::time::{Duration, Instant};
 
-use crate::models;
-use actix::Arbiter;
-use actix_web::web::{Bytes, Data};
-use chrono::{prelude::*, Datelike, TimeZone};
-use serde::Serialize;
-use std::sync::atomic::{AtomicU64, Ordering};
+use crate::cluster::{Notice, Published, Snapshot};
+use crate::error::{ChatError, ErrorBody};
+use crate::mentions;
+use crate::models::{self, HistoryEntry, Reactions, UserMsg};
+use actix::{Actor, Addr, AsyncContext, Handler, Message, MessageResult};
+use actix_web::web::Bytes;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::task::Context;
 use tokio::macros::support::{Pin, Poll};
 use tokio::stream::Stream;
 use tokio::sync::mpsc;
-use tokio::time;
 
-static ID_COUNTER: AtomicU64 = AtomicU64::new(0);
+pub const DEFAULT_ROOM: &str = "main";
+const MAX_ROOM_NAME_LEN: usize = 64;
+/// Longest reaction allowed, in chars. Leaves room for ZWJ sequences and skin tones.
+const MAX_REACTION_LEN: usize = 8;
 
+const CHANNEL_CAPACITY: usize = 100;
+/// How many recent events a room keeps for clients resuming their stream.
+/// Has to fit in a user's channel, since the missed events are queued at once.
+const BACKLOG_LEN: usize = 50;
+/// Minimum time between two typing events of a user in the same room
+const TYPING_THROTTLE: Duration = Duration::from_secs(2);
+/// How long clients show a typing indicator without hearing from the user again
+const TYPING_EXPIRY_SECS: u64 = 5;
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Actor owning every connected user and room. Handlers talk to it through the
+/// messages at the end of this file, so that nothing ever locks the chat state.
+#[derive(Default)]
 pub struct Broadcaster {
-	pub users: Vec<User>,
-	pub history: Vec<UserMsg>,
+	users: HashMap<u64, User>,
+	rooms: HashMap<String, Room>,
+	/// Where events are published for the other instances, if there are any
+	cluster: Option<mpsc::UnboundedSender<Published>>,
+	/// Users connected to the other instances, as of their last snapshots
+	peers: Vec<Peer>,
+	mutes: Mutes,
+}
+
+impl Actor for Broadcaster {
+	type Context = actix::Context<Self>;
+
+	fn started(&mut self, ctx: &mut Self::Context) {
+		ctx.run_interval(HEARTBEAT_INTERVAL, |broadcaster, _| {
+			broadcaster.remove_dead_users();
+			broadcaster.mutes.prune(Instant::now());
+			broadcaster.share();
+		});
+	}
+}
+
+/// Muted nicks and addresses. Kept apart from the users, so that reconnecting
+/// doesn't lift a mute. Nicks are stored lowercase.
+#[derive(Default)]
+struct Mutes {
+	nicks: HashMap<String, Instant>,
+	ips: HashMap<IpAddr, Instant>,
+}
+
+impl Mutes {
+	fn add(&mut self, nick: &str, ip: Option<IpAddr>, until: Instant) {
+		self.nicks.insert(nick.to_lowercase(), until);
+		if let Some(ip) = ip {
+			self.ips.insert(ip, until);
+		}
+	}
+
+	/// How long `nick` or `ip` stay muted, whichever is longer, if they are.
+	fn remaining(&self, nick: &str, ip: Option<IpAddr>, now: Instant) -> Option<Duration> {
+		let nick_until = self.nicks.get(&nick.to_lowercase());
+		let ip_until = ip.and_then(|ip| self.ips.get(&ip));
+		nick_until
+			.into_iter()
+			.chain(ip_until)
+			.max()
+			.filter(|&&until| until > now)
+			.map(|&until| until - now)
+	}
+
+	fn prune(&mut self, now: Instant) {
+		self.nicks.retain(|_, until| *until > now);
+		self.ips.retain(|_, until| *until > now);
+	}
+
+	/// Adds the mutes of another instance, keeping the longest of both.
+	fn merge(&mut self, snapshot: &Snapshot, now: Instant) {
+		let until = |secs: u64| now + Duration::from_secs(secs);
+		for (nick, secs) in &snapshot.muted_nicks {
+			let entry = self.nicks.entry(nick.to_lowercase()).or_insert(now);
+			*entry = (*entry).max(until(*secs));
+		}
+		for (ip, secs) in &snapshot.muted_ips {
+			let entry = self.ips.entry(*ip).or_insert(now);
+			*entry = (*entry).max(until(*secs));
+		}
+	}
+
+	/// Seconds left of every mute, for the other instances
+	fn remaining_secs<K: Clone>(mutes: &HashMap<K, Instant>, now: Instant) -> Vec<(K, u64)> {
+		mutes
+			.iter()
+			.filter(|(_, until)| **until > now)
+			.map(|(key, until)| (key.clone(), (*until - now).as_secs().saturating_add(1)))
+			.collect()
+	}
 }
 
-impl Default for Broadcaster {
+pub struct Room {
+	last_event_id: u64,
+	backlog: VecDeque<Event>,
+}
+
+impl Default for Room {
 	fn default() -> Self {
-		Broadcaster {
-			users: Vec::new(),
-			history: Vec::new(),
+		Room {
+			// Seeded with the current time, so that ids from before a restart
+			// or from a previous instance of the room are never reused
+			last_event_id: Utc::now().timestamp_millis() as u64,
+			backlog: VecDeque::with_capacity(BACKLOG_LEN),
+		}
+	}
+}
+
+impl Room {
+	/// Assigns the next event id to `event` and keeps it in the backlog.
+	fn record(&mut self, event: &mut Event) {
+		self.last_event_id += 1;
+		event.id = Some(self.last_event_id);
+
+		if self.backlog.len() == BACKLOG_LEN {
+			self.backlog.pop_front();
 		}
+		self.backlog.push_back(event.clone());
+	}
+
+	fn forget_message(&mut self, message_id: i64) {
+		self.backlog
+			.retain(|event| event.message_id != Some(message_id));
+	}
+
+	pub fn last_event_id(&self) -> u64 {
+		self.last_event_id
+	}
+
+	/// Whether every event after `last_id` is still in the backlog.
+	pub fn can_resume(&self, last_id: u64) -> bool {
+		let oldest_id = self
+			.backlog
+			.front()
+			.and_then(|event| event.id)
+			.unwrap_or(self.last_event_id + 1);
+		last_id <= self.last_event_id && last_id + 1 >= oldest_id
+	}
+
+	pub fn events_since(&self, last_id: u64) -> impl Iterator<Item = &Event> {
+		self.backlog
+			.iter()
+			.filter(move |event| event.id.is_some_and(|id| id > last_id))
 	}
 }
 
 impl Broadcaster {
-	pub fn new() -> Data<Mutex<Self>> {
-		let broadcaster = Data::new(Mutex::new(Broadcaster::default()));
-		Broadcaster::start_heartbeat(broadcaster.clone());
-		Broadcaster::start_history_cleaner(broadcaster.clone());
-		broadcaster
+	pub fn new(cluster: Option<mpsc::UnboundedSender<Published>>) -> Addr<Self> {
+		Broadcaster {
+			cluster,
+			..Broadcaster::default()
+		}
+		.start()
 	}
 
-	fn start_heartbeat(broadcaster: Data<Mutex<Broadcaster>>) {
-		Arbiter::spawn(Self::heartbeat(broadcaster));
+	/// Hands `notice` over to be published to the other instances.
+	fn publish(&self, notice: Notice) {
+		if let Some(cluster) = &self.cluster {
+			let _ = cluster.send(Published::Notice(notice));
+		}
 	}
 
-	async fn heartbeat(broadcaster: Data<Mutex<Broadcaster>>) {
-		let mut timer = time::interval(Duration::from_secs(10));
-		loop {
-			let _ = timer.tick().await;
-			broadcaster.lock().unwrap().remove_dead_users();
+	/// Hands the local users and mutes over to be saved for the other instances.
+	fn share(&self) {
+		let cluster = match &self.cluster {
+			Some(cluster) => cluster,
+			None => return,
+		};
+		let mut users = self.users.values().map(User::peer).collect::<Vec<Peer>>();
+		users.sort_by_key(|peer| peer.id);
+
+		let now = Instant::now();
+		let _ = cluster.send(Published::Snapshot(Snapshot {
+			users,
+			muted_nicks: Mutes::remaining_secs(&self.mutes.nicks, now),
+			muted_ips: Mutes::remaining_secs(&self.mutes.ips, now),
+		}));
+	}
+
+	/// Delivers `notice` to the local users, then publishes it.
+	fn announce(&mut self, notice: Notice) {
+		self.apply(&notice);
+		self.publish(notice);
+	}
+
+	/// Delivers an event from this or another instance to the local users.
+	fn apply(&mut self, notice: &Notice) {
+		match notice {
+			Notice::MessageDeleted { room, id } => self.send_delete(room, *id),
+			Notice::ReactionsUpdated { room, reactions } => self.send_reactions(room, reactions),
+			Notice::UserJoined { room, presence } => self.broadcast(
+				room,
+				event_data(Msg::user_joined_msg(presence).in_room(room)),
+			),
+			Notice::UserLeft { room, presence } => {
+				self.broadcast(room, event_data(Msg::user_left_msg(presence).in_room(room)))
+			}
+			Notice::UserRenamed { room, renamed } => self.broadcast(
+				room,
+				event_data(Msg::user_renamed_msg(renamed).in_room(room)),
+			),
+			Notice::Typing { room, typing } => self.fan_out(
+				room,
+				None,
+				event_data(Msg::typing_msg(typing).in_room(room)),
+			),
+			Notice::Muted { nick, ip, secs } => {
+				let until = Instant::now() + Duration::from_secs(*secs);
+				self.mutes.add(nick, *ip, until);
+			}
+			Notice::Kicked { nick } => {
+				if let Some(id) = self.find_local(nick).map(|user| user.id) {
+					self.kick(id);
+					self.share();
+				}
+			}
+			Notice::PrivateMessage(private_msg) => {
+				let to = private_msg.to.to_lowercase();
+				let msg = event_data(Msg::private_msg(private_msg));
+				for user in self
+					.users
+					.values_mut()
+					.filter(|u| u.nick.to_lowercase() == to)
+				{
+					user.send(msg.clone());
+				}
+			}
+			// Loaded from the database by `cluster::relay`, and applied as `Remote`
+			Notice::MessageSent(_)
+			| Notice::MessageEdited(_)
+			| Notice::PasteSent(_)
+			| Notice::PresencesChanged => (),
+		}
+	}
+
+	/// Drops closed streams. A user is gone only once their last stream is closed,
+	/// and leaves a room once their last stream in that room is closed.
+	///
+	/// Streams that are merely full belong to slow clients, and are kept.
+	fn remove_dead_users(&mut self) {
+		let mut left = Vec::new();
+		for user in self.users.values_mut() {
+			let rooms_before = user.rooms();
+			user.streams.retain_mut(UserStream::ping);
+			for room in rooms_before {
+				if !user.is_in_room(&room) {
+					user.last_typing.remove(&room);
+					left.push((room, user.presence()));
+				}
+			}
+		}
+		self.users.retain(|_, user| !user.streams.is_empty());
+
+		for (room, presence) in left {
+			self.announce(Notice::UserLeft { room, presence });
 		}
+
+		let users = &self.users;
+		self.rooms
+			.retain(|name, _| users.values().any(|user| user.is_in_room(name)));
+	}
+
+	fn user_mut(&mut self, id: u64) -> Option<&mut User> {
+		self.users.get_mut(&id)
 	}
 
-	fn start_history_cleaner(broadcaster: Data<Mutex<Broadcaster>>) {
-		Arbiter::spawn(Self::history_cleaner(broadcaster));
+	fn user_info(&self, user: &User) -> UserInfo {
+		UserInfo {
+			nick: user.nick.clone(),
+			color: user.color.clone(),
+			is_moderator: user.is_moderator,
+			muted_for: self.mutes.remaining(&user.nick, user.ip, Instant::now()),
+			rooms: user.rooms(),
+			ip: user.ip,
+		}
 	}
 
-	async fn history_cleaner(broadcaster: Data<Mutex<Broadcaster>>) {
-		let now = chrono::Local::now();
-		let midnight = chrono::Local
-			.ymd(now.year(), now.month(), now.day())
-			.and_hms(23, 59, 59);
-		let now_std = Instant::now();
-		let i = now_std + ((midnight - now).to_std().expect("Failed to calc date"));
+	/// Closes every stream of the user `id` after letting them know they got kicked.
+	fn kick(&mut self, id: u64) {
+		let mut user = match self.users.remove(&id) {
+			Some(user) => user,
+			None => return,
+		};
+		user.send(event_data(Msg::new(MsgType::Kicked)));
 
-		let mut timer = time::interval_at(
-			time::Instant::from_std(i),
-			Duration::from_secs(60 * 60 * 24),
-		);
+		for room in user.rooms() {
+			self.announce(Notice::UserLeft {
+				room,
+				presence: user.presence(),
+			});
+		}
+
+		let users = &self.users;
+		self.rooms
+			.retain(|name, _| users.values().any(|user| user.is_in_room(name)));
+	}
+
+	/// Whether a user other than `id` uses `nick`, ignoring case, here or on
+	/// another instance. The same session can still be listed by an instance it
+	/// just moved away from.
+	fn is_nick_taken(&self, nick: &str, id: Option<u64>) -> bool {
+		let nick = nick.to_lowercase();
+		self.users
+			.values()
+			.map(|user| (user.id, &user.nick))
+			.chain(self.peers.iter().map(|peer| (peer.id, &peer.nick)))
+			.any(|(user_id, user_nick)| Some(user_id) != id && user_nick.to_lowercase() == nick)
+	}
+
+	fn find_local(&self, nick: &str) -> Option<&User> {
+		let nick = nick.to_lowercase();
+		self.users
+			.values()
+			.find(|user| user.nick.to_lowercase() == nick)
+	}
+
+	/// Users connected to another instance, leaving out the ones that also
+	/// connected here
+	fn remote_peers(&self) -> impl Iterator<Item = &Peer> {
+		self.peers
+			.iter()
+			.filter(move |peer| !self.users.contains_key(&peer.id))
+	}
+
+	/// Users with at least one stream open in `room`, or everyone if `room` is None.
+	fn online(&self, room: Option<&str>) -> Vec<Presence> {
+		let remote = self
+			.remote_peers()
+			.filter(|peer| room.is_none_or(|room| peer.rooms.iter().any(|r| r == room)))
+			.map(Peer::presence);
+		self.users
+			.values()
+			.filter(|user| room.is_none_or(|room| user.is_in_room(room)))
+			.map(User::presence)
+			.chain(remote)
+			.collect()
+	}
+
+	/// Opens a new stream in `room` for the user `id`. The user is created if the
+	/// session doesn't have one yet, otherwise the stream is attached to them.
+	fn connect(
+		&mut self,
+		id: Option<u64>,
+		room: &str,
+		nick: &str,
+		color: Option<String>,
+		ip: Option<IpAddr>,
+	) -> (mpsc::Receiver<Event>, &mut User) {
+		let (mut tx, rx) = mpsc::channel(CHANNEL_CAPACITY);
+
+		tx.try_send(event_data(Msg::new(MsgType::Ping))).unwrap();
+
+		self.rooms.entry(String::from(room)).or_default();
+
+		let id = id.unwrap_or_else(|| self.new_user_id());
+		match self.users.get(&id) {
+			Some(_) => (),
+			None => {
+				self.users.insert(
+					id,
+					User {
+						id,
+						nick: String::from(nick),
+						color: None,
+						is_moderator: false,
+						ip,
+						streams: Vec::new(),
+						last_typing: HashMap::new(),
+					},
+				);
+			}
+		}
+
+		let user = self.users.get_mut(&id).unwrap();
+		user.color = color;
+		user.ip = ip.or(user.ip);
+		let joined = !user.is_in_room(room);
+		user.streams.push(UserStream::new(room, tx));
+
+		if joined {
+			let presence = user.presence();
+			self.announce(Notice::UserJoined {
+				room: String::from(room),
+				presence,
+			});
+		}
+
+		(rx, self.users.get_mut(&id).unwrap())
+	}
+
+	/// User ids are random instead of sequential, since they're stored as message
+	/// authors and have to stay unique across restarts.
+	fn new_user_id(&self) -> u64 {
 		loop {
-			let _ = timer.tick().await;
-			broadcaster.lock().unwrap().history.clear();
+			// Has to fit in a BIGINT column
+			let id = rand::random::<u64>() >> 1;
+			if !self.users.contains_key(&id) {
+				return id;
+			}
 		}
 	}
 
-	fn remove_dead_users(&mut self) {
-		self.users = self
+	/// Changes the nick of the user `id` and lets every room they're in know.
+	fn rename(&mut self, id: u64, nick: String) {
+		let user = match self.user_mut(id) {
+			Some(user) => user,
+			None => return,
+		};
+
+		user.send(event_data(Msg::nick_change_msg(&nick)));
+		let renamed = Renamed {
+			old_nick: std::mem::replace(&mut user.nick, nick.clone()),
+			new_nick: nick,
+		};
+
+		for room in user.rooms() {
+			self.announce(Notice::UserRenamed {
+				room,
+				renamed: renamed.clone(),
+			});
+		}
+	}
+
+	/// Records `event` in the backlog of `room_name` and sends it to every stream
+	/// opened in that room.
+	fn broadcast(&mut self, room_name: &str, mut event: Event) {
+		let room = match self.rooms.get_mut(room_name) {
+			Some(room) => room,
+			None => return,
+		};
+		room.record(&mut event);
+		self.fan_out(room_name, None, event);
+	}
+
+	/// Sends `event` to every stream in `room_name` except the ones of the user
+	/// `skip`, without recording it in the backlog.
+	fn fan_out(&mut self, room_name: &str, skip: Option<u64>, event: Event) {
+		for stream in self
 			.users
-			.iter()
-			.filter_map(|user| {
-				let mut user = user.clone();
-				if user
-					.sender
-					.try_send(event_data(Msg::new(MsgType::Ping)))
-					.is_ok()
-				{
-					Some(user)
-				} else {
-					None
-				}
+			.values_mut()
+			.filter(|user| Some(user.id) != skip)
+			.flat_map(|user| user.streams.iter_mut())
+			.filter(|stream| stream.room == room_name)
+		{
+			stream.deliver(event.clone());
+		}
+	}
+
+	/// Broadcasts an already persisted message to everyone in its room, and sends
+	/// an extra `Mention` event to each connected user it mentions with `@nick`.
+	///
+	/// Returns the nicks of the mentioned users.
+	fn send(&mut self, user_msg: &UserMsg) -> Vec<String> {
+		let mut event = event_data(Msg::user_msg(user_msg).in_room(&user_msg.room));
+		event.message_id = Some(user_msg.id);
+		self.broadcast(&user_msg.room, event);
+
+		let mentioned = mentions::parse(&user_msg.msg)
+			.into_iter()
+			.map(str::to_lowercase)
+			.collect::<Vec<String>>();
+		if mentioned.is_empty() {
+			return Vec::new();
+		}
+
+		let mention = event_data(Msg::mention_msg(user_msg).in_room(&user_msg.room));
+		self.users
+			.values_mut()
+			.filter(|user| user.id as i64 != user_msg.author_id)
+			.filter(|user| mentioned.contains(&user.nick.to_lowercase()))
+			.map(|user| {
+				user.send(mention.clone());
+				user.nick.clone()
 			})
-			.collect::<Vec<User>>();
+			.collect()
 	}
 
-	pub fn new_user(&mut self, nick: &str) -> (UserDataStream, &mut User) {
-		let (mut tx, rx) = mpsc::channel(100);
+	fn send_edit(&mut self, user_msg: &UserMsg) {
+		let mut event = event_data(Msg::message_edited_msg(user_msg).in_room(&user_msg.room));
+		event.message_id = Some(user_msg.id);
+		self.broadcast(&user_msg.room, event);
+	}
 
-		tx.try_send(event_data(Msg::new(MsgType::Ping))).unwrap();
+	/// Sends a tombstone for a deleted message, and drops every event carrying its
+	/// content from the backlog, so that resuming clients never see it.
+	fn send_delete(&mut self, room_name: &str, id: i64) {
+		if let Some(room) = self.rooms.get_mut(room_name) {
+			room.forget_message(id);
+		}
+		let deleted = DeletedMsg { id };
+		self.broadcast(
+			room_name,
+			event_data(Msg::message_deleted_msg(&deleted).in_room(room_name)),
+		);
+	}
+
+	/// Lets everyone else in `room` know the user `id` is typing. Events are
+	/// throttled per user, and never make it into the history or the backlog.
+	///
+	/// Returns false if the user isn't in `room`.
+	fn typing(&mut self, id: u64, room: &str) -> bool {
+		let user = match self.user_mut(id) {
+			Some(user) if user.is_in_room(room) => user,
+			_ => return false,
+		};
+
+		let now = Instant::now();
+		if user
+			.last_typing
+			.get(room)
+			.is_some_and(|&last| now.duration_since(last) < TYPING_THROTTLE)
+		{
+			return true;
+		}
+		user.last_typing.insert(String::from(room), now);
 
-		self.users.push(User {
-			id: ID_COUNTER.fetch_add(1, Ordering::SeqCst),
-			nick: String::from(nick),
-			color: None,
-			sender: tx.clone(),
+		let typing = Typing {
+			nick: user.nick.clone(),
+			expires_in: TYPING_EXPIRY_SECS,
+		};
+		self.fan_out(
+			room,
+			Some(id),
+			event_data(Msg::typing_msg(&typing).in_room(room)),
+		);
+		self.publish(Notice::Typing {
+			room: String::from(room),
+			typing,
 		});
+		true
+	}
 
-		(UserDataStream(rx), self.users.last_mut().unwrap())
+	/// Broadcasts the new reaction counts of a message to its room.
+	fn send_reactions(&mut self, room: &str, reactions: &ReactionsMsg) {
+		let mut event = event_data(Msg::reaction_updated_msg(reactions).in_room(room));
+		event.message_id = Some(reactions.message_id);
+		self.broadcast(room, event);
 	}
 
-	pub fn send(&mut self, id: u64, msg: String) {
-		let user = self.users.iter_mut().find(|u| u.id == id).unwrap();
+	/// Delivers `msg` only to the sender and every connection of the user
+	/// nicknamed `to`, ignoring case. Private messages are never kept in the room
+	/// history.
+	///
+	/// Returns false if either the sender or the recipient isn't connected.
+	fn send_private(&mut self, id: u64, to: &str, msg: String) -> bool {
+		let (recipient, recipient_nick) = match self.find_local(to) {
+			Some(recipient) => (Some(recipient.id), recipient.nick.clone()),
+			None => {
+				let to = to.to_lowercase();
+				match self
+					.remote_peers()
+					.find(|peer| peer.nick.to_lowercase() == to)
+				{
+					Some(peer) => (None, peer.nick.clone()),
+					None => return false,
+				}
+			}
+		};
+		let (sender, sender_color) = match self.users.get(&id) {
+			Some(user) => (user.nick.clone(), user.color.clone()),
+			None => return false,
+		};
 
-		let user_msg = UserMsg {
-			nick: user.nick.clone(),
-			custom_nick_color: user.color.clone(),
+		let private_msg = PrivateMsg {
+			from: sender,
+			to: recipient_nick,
+			custom_nick_color: sender_color,
 			msg,
 			time: Utc::now(),
 		};
+		let msg = event_data(Msg::private_msg(&private_msg));
 
-		let msg = event_data(Msg::user_msg(&user_msg));
-
-		self.history.push(user_msg);
-
-		for user in &mut self.users {
-			user.sender.try_send(msg.clone()).unwrap_or(());
+		for user in self
+			.users
+			.values_mut()
+			.filter(|u| u.id == id || Some(u.id) == recipient)
+		{
+			user.send(msg.clone());
 		}
+		if recipient.is_none() {
+			self.publish(Notice::PrivateMessage(private_msg));
+		}
+		true
 	}
 
-	pub fn send_paste(&mut self, paste: models::Paste) {
+	fn send_paste(&mut self, paste: models::ListedPaste) {
 		let msg = event_data(Msg::paste_msg(&paste));
 
-		for user in &mut self.users {
-			user.sender.try_send(msg.clone()).unwrap_or(());
+		let rooms = self.rooms.keys().cloned().collect::<Vec<String>>();
+		for room in rooms {
+			self.broadcast(&room, msg.clone());
 		}
 	}
 }
 
+pub fn is_valid_room_name(room: &str) -> bool {
+	!room.is_empty() && room.len() <= MAX_ROOM_NAME_LEN && !room.contains(char::is_control)
+}
+
+/// Reactions are meant to be emoji, so words like "+1" or "да" are rejected. A
+/// reaction is a single pictograph, possibly followed by skin tones, variation
+/// selectors and more pictographs joined with ZWJ, or a keycap like "1️⃣".
+pub fn is_valid_reaction(emoji: &str) -> bool {
+	let mut chars = emoji.chars();
+	let first = match chars.next() {
+		Some(first) => first,
+		None => return false,
+	};
+	let keycap = emoji.ends_with('\u{20E3}');
+	let base = is_pictograph(first) || (keycap && matches!(first, '0'..='9' | '#' | '*'));
+
+	base && emoji.chars().count() <= MAX_REACTION_LEN
+		&& chars.all(|c| is_pictograph(c) || is_emoji_component(c))
+}
+
+/// Code points of the emoji blocks, along with the few older symbols that have
+/// an emoji presentation
+fn is_pictograph(c: char) -> bool {
+	matches!(c,
+		'\u{00A9}' | '\u{00AE}' | '\u{203C}' | '\u{2049}' | '\u{2122}' | '\u{2139}'
+		| '\u{2194}'..='\u{21FF}'
+		| '\u{2300}'..='\u{23FF}'
+		| '\u{24C2}'
+		| '\u{25A0}'..='\u{25FF}'
+		| '\u{2600}'..='\u{27BF}'
+		| '\u{2900}'..='\u{297F}'
+		| '\u{2B00}'..='\u{2BFF}'
+		| '\u{3030}' | '\u{303D}' | '\u{3297}' | '\u{3299}'
+		| '\u{1F000}'..='\u{1FAFF}')
+}
+
+/// Code points that only modify or join pictographs: ZWJ, variation selectors,
+/// the keycap mark and the tags of subdivision flags
+fn is_emoji_component(c: char) -> bool {
+	matches!(
+		c,
+		'\u{200D}' | '\u{20E3}' | '\u{FE0E}' | '\u{FE0F}' | '\u{E0020}'..='\u{E007F}'
+	)
+}
+
 #[derive(Serialize)]
 pub struct Msg<T> {
 	r#type: MsgType,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	room: Option<String>,
 	data: Option<T>,
 }
 
+impl<T> Msg<T> {
+	pub fn in_room(mut self, room: &str) -> Self {
+		self.room = Some(String::from(room));
+		self
+	}
+}
+
 impl<'a> Msg<&'a UserMsg> {
 	pub fn user_msg(msg: &'a UserMsg) -> Self {
 		Msg {
 			r#type: MsgType::Message,
+			room: None,
+			data: Some(msg),
+		}
+	}
+
+	pub fn message_edited_msg(msg: &'a UserMsg) -> Self {
+		Msg {
+			r#type: MsgType::MessageEdited,
+			room: None,
+			data: Some(msg),
+		}
+	}
+
+	pub fn mention_msg(msg: &'a UserMsg) -> Self {
+		Msg {
+			r#type: MsgType::Mention,
+			room: None,
+			data: Some(msg),
+		}
+	}
+}
+
+impl<'a> Msg<&'a DeletedMsg> {
+	pub fn message_deleted_msg(msg: &'a DeletedMsg) -> Self {
+		Msg {
+			r#type: MsgType::MessageDeleted,
+			room: None,
+			data: Some(msg),
+		}
+	}
+}
+
+impl<'a> Msg<&'a ReactionsMsg> {
+	pub fn reaction_updated_msg(reactions: &'a ReactionsMsg) -> Self {
+		Msg {
+			r#type: MsgType::ReactionUpdated,
+			room: None,
+			data: Some(reactions),
+		}
+	}
+}
+
+impl<'a> Msg<&'a Typing> {
+	pub fn typing_msg(typing: &'a Typing) -> Self {
+		Msg {
+			r#type: MsgType::Typing,
+			room: None,
+			data: Some(typing),
+		}
+	}
+}
+
+impl<'a> Msg<&'a RateLimited> {
+	pub fn rate_limited_msg(rate_limited: &'a RateLimited) -> Self {
+		Msg {
+			r#type: MsgType::RateLimited,
+			room: None,
+			data: Some(rate_limited),
+		}
+	}
+}
+
+impl<'a> Msg<&'a PrivateMsg> {
+	pub fn private_msg(msg: &'a PrivateMsg) -> Self {
+		Msg {
+			r#type: MsgType::PrivateMessage,
+			room: None,
 			data: Some(msg),
 		}
 	}
@@ -150,23 +752,67 @@ impl<'a> Msg<&'a UserMsg> {
 
 impl Msg<()> {
 	fn new(r#type: MsgType) -> Self {
-		Msg { r#type, data: None }
+		Msg {
+			r#type,
+			room: None,
+			data: None,
+		}
 	}
 }
 
-impl<'a> Msg<&'a Vec<UserMsg>> {
-	pub fn connected(history: &'a Vec<UserMsg>) -> Self {
+impl<'a> Msg<&'a Vec<HistoryEntry>> {
+	pub fn connected(history: &'a Vec<HistoryEntry>) -> Self {
 		Msg {
 			r#type: MsgType::Connected,
+			room: None,
 			data: Some(history),
 		}
 	}
 }
 
-impl<'a> Msg<&'a models::Paste> {
-	pub fn paste_msg(paste: &'a models::Paste) -> Self {
+impl<'a> Msg<&'a ErrorBody> {
+	pub fn error_msg(error: &'a ErrorBody) -> Self {
+		Msg {
+			r#type: MsgType::Error,
+			room: None,
+			data: Some(error),
+		}
+	}
+}
+
+impl<'a> Msg<&'a Presence> {
+	pub fn user_joined_msg(presence: &'a Presence) -> Self {
+		Msg {
+			r#type: MsgType::UserJoined,
+			room: None,
+			data: Some(presence),
+		}
+	}
+
+	pub fn user_left_msg(presence: &'a Presence) -> Self {
+		Msg {
+			r#type: MsgType::UserLeft,
+			room: None,
+			data: Some(presence),
+		}
+	}
+}
+
+impl<'a> Msg<&'a Renamed> {
+	pub fn user_renamed_msg(renamed: &'a Renamed) -> Self {
+		Msg {
+			r#type: MsgType::UserRenamed,
+			room: None,
+			data: Some(renamed),
+		}
+	}
+}
+
+impl<'a> Msg<&'a models::ListedPaste> {
+	pub fn paste_msg(paste: &'a models::ListedPaste) -> Self {
 		Msg {
 			r#type: MsgType::Paste,
+			room: None,
 			data: Some(paste),
 		}
 	}
@@ -176,6 +822,7 @@ impl<'a> Msg<&'a str> {
 	pub fn color_change_msg(color: &'a str) -> Self {
 		Msg {
 			r#type: MsgType::ColorChange,
+			room: None,
 			data: Some(color),
 		}
 	}
@@ -183,6 +830,7 @@ impl<'a> Msg<&'a str> {
 	pub fn nick_change_msg(nick: &'a str) -> Self {
 		Msg {
 			r#type: MsgType::NickChange,
+			room: None,
 			data: Some(nick),
 		}
 	}
@@ -193,44 +841,920 @@ enum MsgType {
 	Connected,
 	Ping,
 	Message,
+	MessageEdited,
+	MessageDeleted,
+	ReactionUpdated,
+	Mention,
+	Typing,
+	RateLimited,
+	Kicked,
+	/// Events were dropped because the client was too slow, it has to refetch
+	/// the history
+	Resync,
+	PrivateMessage,
 	Paste,
 	NickChange,
 	ColorChange,
+	UserJoined,
+	UserLeft,
+	UserRenamed,
+	Error,
 }
 
 #[derive(Serialize)]
-pub struct UserMsg {
-	pub nick: String,
+pub struct DeletedMsg {
+	pub id: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ReactionsMsg {
+	pub message_id: i64,
+	pub reactions: Reactions,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PrivateMsg {
+	pub from: String,
+	pub to: String,
 	pub custom_nick_color: Option<String>,
 	pub msg: String,
 	pub time: DateTime<Utc>,
 }
 
-#[derive(Clone)]
+/// Chat participant, shared by every stream opened within the same session
 pub struct User {
 	pub id: u64,
 	pub nick: String,
 	pub color: Option<String>,
-	pub sender: mpsc::Sender<Bytes>,
+	pub is_moderator: bool,
+	/// Address the user last connected from
+	pub ip: Option<IpAddr>,
+	pub streams: Vec<UserStream>,
+	/// When the user's last typing event was sent, per room
+	last_typing: HashMap<String, Instant>,
+}
+
+impl User {
+	/// Sends `event` to every stream of the user.
+	pub fn send(&mut self, event: Event) {
+		for stream in &mut self.streams {
+			stream.deliver(event.clone());
+		}
+	}
+
+	pub fn is_in_room(&self, room: &str) -> bool {
+		self.streams.iter().any(|stream| stream.room == room)
+	}
+
+	/// Rooms the user has a stream open in
+	pub fn rooms(&self) -> Vec<String> {
+		let mut rooms = self
+			.streams
+			.iter()
+			.map(|stream| stream.room.clone())
+			.collect::<Vec<String>>();
+		rooms.sort();
+		rooms.dedup();
+		rooms
+	}
+
+	pub fn presence(&self) -> Presence {
+		Presence {
+			nick: self.nick.clone(),
+			color: self.color.clone(),
+		}
+	}
+
+	pub fn peer(&self) -> Peer {
+		Peer {
+			id: self.id,
+			nick: self.nick.clone(),
+			color: self.color.clone(),
+			rooms: self.rooms(),
+			ip: self.ip,
+		}
+	}
+}
+
+/// User connected to this instance or another one of the cluster
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct Peer {
+	pub id: u64,
+	pub nick: String,
+	pub color: Option<String>,
+	pub rooms: Vec<String>,
+	pub ip: Option<IpAddr>,
+}
+
+impl Peer {
+	pub fn presence(&self) -> Presence {
+		Presence {
+			nick: self.nick.clone(),
+			color: self.color.clone(),
+		}
+	}
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Presence {
+	pub nick: String,
+	pub color: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Typing {
+	pub nick: String,
+	/// Seconds after which the indicator should be hidden
+	pub expires_in: u64,
+}
+
+#[derive(Serialize)]
+pub struct RateLimited {
+	/// Seconds until the user may try again
+	pub retry_after: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Renamed {
+	pub old_nick: String,
+	pub new_nick: String,
+}
+
+pub struct UserStream {
+	pub room: String,
+	sender: mpsc::Sender<Event>,
+	/// Set once an event had to be dropped because the client didn't keep up
+	lagging: bool,
+}
+
+impl UserStream {
+	fn new(room: &str, sender: mpsc::Sender<Event>) -> Self {
+		UserStream {
+			room: String::from(room),
+			sender,
+			lagging: false,
+		}
+	}
+
+	/// Queues `event`, unless the client is lagging behind. Events are dropped
+	/// while it lags, then it is sent a `Resync` as soon as there's room again, so
+	/// that it knows to refetch the history.
+	pub fn deliver(&mut self, event: Event) {
+		if !self.resync() {
+			return;
+		}
+		if let Err(mpsc::error::TrySendError::Full(_)) = self.sender.try_send(event) {
+			self.lagging = true;
+		}
+	}
+
+	/// Sends a `Resync` to a lagging client. Returns whether it's caught up.
+	fn resync(&mut self) -> bool {
+		if self.lagging {
+			let resync = event_data(Msg::new(MsgType::Resync).in_room(&self.room));
+			self.lagging = self.sender.try_send(resync).is_err();
+		}
+		!self.lagging
+	}
+
+	/// Checks on the client. Returns false once it's gone, but not if it's only
+	/// slow to read its events.
+	fn ping(&mut self) -> bool {
+		self.resync();
+		match self.sender.try_send(event_data(Msg::new(MsgType::Ping))) {
+			Ok(()) | Err(mpsc::error::TrySendError::Full(_)) => true,
+			Err(mpsc::error::TrySendError::Closed(_)) => false,
+		}
+	}
 }
 
-pub struct UserDataStream(mpsc::Receiver<Bytes>);
+/// Serialized `Msg`. Events broadcast to a room carry an id, so that a client
+/// can resume its stream after reconnecting.
+#[derive(Clone, Debug)]
+pub struct Event {
+	pub id: Option<u64>,
+	pub data: Bytes,
+	/// Chat message the event carries the content of
+	pub message_id: Option<i64>,
+}
+
+/// Server-sent events stream of a single user
+pub struct UserDataStream(mpsc::Receiver<Event>);
+
+impl UserDataStream {
+	pub fn new(rx: mpsc::Receiver<Event>) -> Self {
+		UserDataStream(rx)
+	}
+}
 
 impl Stream for UserDataStream {
 	type Item = Result<Bytes, actix_web::Error>;
 
 	fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-		self.0.poll_recv(cx).map(|opt| opt.map(|b| Ok(b)))
+		self.0.poll_recv(cx).map(|opt| {
+			opt.map(|event| {
+				let id = match event.id {
+					Some(id) => format!("id: {}\n", id),
+					None => String::new(),
+				};
+				Ok([id.as_bytes(), b"data: ", &event.data, b"\n\n"]
+					.concat()
+					.into())
+			})
+		})
 	}
 }
 
-pub fn event_data(msg: Msg<impl Serialize>) -> Bytes {
-	Bytes::from(
-		[
-			"data: ",
-			serde_json::to_string(&msg).unwrap().as_str(),
-			"\n\n",
-		]
-		.concat(),
-	)
+/// Serializes `msg` into an event without an id.
+pub fn event_data(msg: Msg<impl Serialize>) -> Event {
+	Event {
+		id: None,
+		data: Bytes::from(serde_json::to_vec(&msg).unwrap()),
+		message_id: None,
+	}
+}
+
+/// Snapshot of a connected user, as seen by the handlers
+pub struct UserInfo {
+	pub nick: String,
+	pub color: Option<String>,
+	pub is_moderator: bool,
+	/// How long the user stays muted, if they are
+	pub muted_for: Option<Duration>,
+	pub rooms: Vec<String>,
+	pub ip: Option<IpAddr>,
+}
+
+impl UserInfo {
+	pub fn is_in_room(&self, room: &str) -> bool {
+		self.rooms.iter().any(|r| r == room)
+	}
+}
+
+/// Looks up a connected user by id
+#[derive(Message)]
+#[rtype(result = "Option<UserInfo>")]
+pub struct GetUser {
+	pub id: u64,
+}
+
+impl Handler<GetUser> for Broadcaster {
+	type Result = Option<UserInfo>;
+
+	fn handle(&mut self, msg: GetUser, _: &mut Self::Context) -> Self::Result {
+		self.users.get(&msg.id).map(|user| self.user_info(user))
+	}
+}
+
+/// Looks up a user connected to any instance by nick, ignoring case
+#[derive(Message)]
+#[rtype(result = "Option<Peer>")]
+pub struct FindUser {
+	pub nick: String,
+}
+
+impl Handler<FindUser> for Broadcaster {
+	type Result = Option<Peer>;
+
+	fn handle(&mut self, msg: FindUser, _: &mut Self::Context) -> Self::Result {
+		if let Some(user) = self.find_local(&msg.nick) {
+			return Some(user.peer());
+		}
+		let nick = msg.nick.to_lowercase();
+		self.remote_peers()
+			.find(|peer| peer.nick.to_lowercase() == nick)
+			.cloned()
+	}
+}
+
+/// Whether a client that last saw `last_event_id` in `room` can catch up from
+/// the backlog alone
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct CanResume {
+	pub room: String,
+	pub last_event_id: u64,
+}
+
+impl Handler<CanResume> for Broadcaster {
+	type Result = bool;
+
+	fn handle(&mut self, msg: CanResume, _: &mut Self::Context) -> Self::Result {
+		self.rooms
+			.get(&msg.room)
+			.is_some_and(|room| room.can_resume(msg.last_event_id))
+	}
+}
+
+/// Id of the last event of a room, or None if nobody is in it
+#[derive(Message)]
+#[rtype(result = "Option<u64>")]
+pub struct LastEventId {
+	pub room: String,
+}
+
+impl Handler<LastEventId> for Broadcaster {
+	type Result = Option<u64>;
+
+	fn handle(&mut self, msg: LastEventId, _: &mut Self::Context) -> Self::Result {
+		self.rooms.get(&msg.room).map(Room::last_event_id)
+	}
+}
+
+/// Opens a stream in `room` and queues either the events missed since
+/// `resume_from`, or the given history and unread mentions. Returns the id and
+/// nick of the user, which keeps its nick if it's still connected.
+#[derive(Message)]
+#[rtype(result = "Result<(u64, String, mpsc::Receiver<Event>), ChatError>")]
+pub struct Connect {
+	/// User of the session, if it has one
+	pub id: Option<u64>,
+	pub room: String,
+	pub nick: String,
+	pub color: Option<String>,
+	pub ip: Option<IpAddr>,
+	pub resume_from: Option<u64>,
+	/// Last event of the room when `history` was loaded, None if the room
+	/// didn't exist yet
+	pub history_loaded_at: Option<u64>,
+	pub history: Vec<HistoryEntry>,
+	pub mentions: Vec<UserMsg>,
+}
+
+impl Handler<Connect> for Broadcaster {
+	type Result = Result<(u64, String, mpsc::Receiver<Event>), ChatError>;
+
+	fn handle(&mut self, msg: Connect, _: &mut Self::Context) -> Self::Result {
+		let Connect {
+			id,
+			room: room_name,
+			nick,
+			color,
+			ip,
+			resume_from,
+			history_loaded_at,
+			history,
+			mentions: unread_mentions,
+		} = msg;
+		// EventSource reconnects reuse the URL they were opened with, which has
+		// the nick from before any /nick
+		let current_nick = id
+			.and_then(|id| self.users.get(&id))
+			.map(|user| user.nick.clone());
+		let renamed = current_nick.as_ref().filter(|current| **current != nick);
+		let nick = current_nick.clone().unwrap_or(nick);
+		if self.is_nick_taken(&nick, id) {
+			return Err(ChatError::Conflict(String::from(
+				"This nick is already taken",
+			)));
+		}
+
+		let (rx, user) = self.connect(id, &room_name, &nick, color, ip);
+		let id = user.id;
+
+		let room = &self.rooms[&room_name];
+		let events = match resume_from {
+			Some(last_id) if room.can_resume(last_id) => {
+				room.events_since(last_id).cloned().collect()
+			}
+			// The backlog moved on since the client was told it could resume
+			Some(_) => vec![event_data(Msg::new(MsgType::Resync).in_room(&room_name))],
+			None => {
+				let mut connected = event_data(Msg::connected(&history).in_room(&room_name));
+				connected.id = Some(room.last_event_id());
+
+				// Broadcast after the history was loaded, but before the stream was
+				// there to get them. Sent without ids, so that resuming starts after
+				// `connected`.
+				let missed = match history_loaded_at {
+					Some(loaded_at) if room.can_resume(loaded_at) => {
+						room.events_since(loaded_at).cloned().collect()
+					}
+					Some(_) => vec![event_data(Msg::new(MsgType::Resync).in_room(&room_name))],
+					None => room.backlog.iter().cloned().collect::<Vec<Event>>(),
+				};
+				let missed = missed.into_iter().map(|event| Event { id: None, ..event });
+
+				let mentions = unread_mentions
+					.iter()
+					.map(|user_msg| event_data(Msg::mention_msg(user_msg).in_room(&room_name)));
+				std::iter::once(connected)
+					.chain(missed)
+					.chain(mentions)
+					.collect()
+			}
+		};
+		let events = renamed
+			.map(|nick| event_data(Msg::nick_change_msg(nick)))
+			.into_iter()
+			.chain(events);
+
+		if let Some(stream) = self
+			.users
+			.get_mut(&id)
+			.and_then(|user| user.streams.last_mut())
+		{
+			for event in events {
+				stream.deliver(event);
+			}
+		}
+		self.share();
+		Ok((id, nick, rx))
+	}
+}
+
+/// Users with a stream open in `room`, or everyone if it's None
+#[derive(Message)]
+#[rtype(result = "Vec<Presence>")]
+pub struct Online {
+	pub room: Option<String>,
+}
+
+impl Handler<Online> for Broadcaster {
+	type Result = MessageResult<Online>;
+
+	fn handle(&mut self, msg: Online, _: &mut Self::Context) -> Self::Result {
+		MessageResult(self.online(msg.room.as_deref()))
+	}
+}
+
+/// Broadcasts a persisted message, returning the nicks it mentions
+#[derive(Message)]
+#[rtype(result = "Vec<String>")]
+pub struct SendMsg(pub UserMsg);
+
+impl Handler<SendMsg> for Broadcaster {
+	type Result = MessageResult<SendMsg>;
+
+	fn handle(&mut self, msg: SendMsg, _: &mut Self::Context) -> Self::Result {
+		let mentioned = self.send(&msg.0);
+		self.publish(Notice::MessageSent(msg.0.id));
+		MessageResult(mentioned)
+	}
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SendEdit(pub UserMsg);
+
+impl Handler<SendEdit> for Broadcaster {
+	type Result = ();
+
+	fn handle(&mut self, msg: SendEdit, _: &mut Self::Context) {
+		self.send_edit(&msg.0);
+		self.publish(Notice::MessageEdited(msg.0.id));
+	}
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SendDelete(pub UserMsg);
+
+impl Handler<SendDelete> for Broadcaster {
+	type Result = ();
+
+	fn handle(&mut self, msg: SendDelete, _: &mut Self::Context) {
+		self.announce(Notice::MessageDeleted {
+			room: msg.0.room,
+			id: msg.0.id,
+		});
+	}
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SendReactions {
+	pub room: String,
+	pub reactions: ReactionsMsg,
+}
+
+impl Handler<SendReactions> for Broadcaster {
+	type Result = ();
+
+	fn handle(&mut self, msg: SendReactions, _: &mut Self::Context) {
+		self.announce(Notice::ReactionsUpdated {
+			room: msg.room,
+			reactions: msg.reactions,
+		});
+	}
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SendPaste(pub models::ListedPaste);
+
+impl Handler<SendPaste> for Broadcaster {
+	type Result = ();
+
+	fn handle(&mut self, msg: SendPaste, _: &mut Self::Context) {
+		let id = msg.0.paste.id;
+		self.send_paste(msg.0);
+		self.publish(Notice::PasteSent(id));
+	}
+}
+
+/// Sends a private message, returning false if either side isn't connected
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct SendPrivate {
+	pub id: u64,
+	pub to: String,
+	pub msg: String,
+}
+
+impl Handler<SendPrivate> for Broadcaster {
+	type Result = bool;
+
+	fn handle(&mut self, msg: SendPrivate, _: &mut Self::Context) -> Self::Result {
+		self.send_private(msg.id, &msg.to, msg.msg)
+	}
+}
+
+/// Sends an event to every stream of a single user
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SendTo {
+	pub id: u64,
+	pub event: Event,
+}
+
+impl Handler<SendTo> for Broadcaster {
+	type Result = ();
+
+	fn handle(&mut self, msg: SendTo, _: &mut Self::Context) {
+		if let Some(user) = self.user_mut(msg.id) {
+			user.send(msg.event);
+		}
+	}
+}
+
+/// Returns false if the user isn't in `room`
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct IsTyping {
+	pub id: u64,
+	pub room: String,
+}
+
+impl Handler<IsTyping> for Broadcaster {
+	type Result = bool;
+
+	fn handle(&mut self, msg: IsTyping, _: &mut Self::Context) -> Self::Result {
+		self.typing(msg.id, &msg.room)
+	}
+}
+
+/// Changes the nick color of a user, None being the default one
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SetColor {
+	pub id: u64,
+	pub color: Option<String>,
+}
+
+impl Handler<SetColor> for Broadcaster {
+	type Result = ();
+
+	fn handle(&mut self, msg: SetColor, _: &mut Self::Context) {
+		if let Some(user) = self.user_mut(msg.id) {
+			user.send(event_data(Msg::color_change_msg(
+				msg.color.as_deref().unwrap_or_default(),
+			)));
+			user.color = msg.color;
+		}
+	}
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<(), ChatError>")]
+pub struct Rename {
+	pub id: u64,
+	pub nick: String,
+}
+
+impl Handler<Rename> for Broadcaster {
+	type Result = Result<(), ChatError>;
+
+	fn handle(&mut self, msg: Rename, _: &mut Self::Context) -> Self::Result {
+		if self.is_nick_taken(&msg.nick, Some(msg.id)) {
+			return Err(ChatError::Conflict(String::from(
+				"This nick is already taken",
+			)));
+		}
+		self.rename(msg.id, msg.nick);
+		self.share();
+		Ok(())
+	}
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SetModerator {
+	pub id: u64,
+}
+
+impl Handler<SetModerator> for Broadcaster {
+	type Result = ();
+
+	fn handle(&mut self, msg: SetModerator, _: &mut Self::Context) {
+		if let Some(user) = self.user_mut(msg.id) {
+			user.is_moderator = true;
+		}
+	}
+}
+
+/// Mutes `nick` and `ip` on every instance
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Mute {
+	pub nick: String,
+	pub ip: Option<IpAddr>,
+	pub until: Instant,
+}
+
+impl Handler<Mute> for Broadcaster {
+	type Result = ();
+
+	fn handle(&mut self, msg: Mute, _: &mut Self::Context) {
+		let secs = msg
+			.until
+			.saturating_duration_since(Instant::now())
+			.as_secs();
+		self.announce(Notice::Muted {
+			nick: msg.nick,
+			ip: msg.ip,
+			secs,
+		});
+	}
+}
+
+/// How long `nick` or `ip` stay muted, if they are. For clients that may not
+/// have a stream open.
+#[derive(Message)]
+#[rtype(result = "Option<Duration>")]
+pub struct MutedFor {
+	pub nick: String,
+	pub ip: Option<IpAddr>,
+}
+
+impl Handler<MutedFor> for Broadcaster {
+	type Result = Option<Duration>;
+
+	fn handle(&mut self, msg: MutedFor, _: &mut Self::Context) -> Self::Result {
+		self.mutes.remaining(&msg.nick, msg.ip, Instant::now())
+	}
+}
+
+/// Kicks the user nicknamed `nick`, on whichever instance they're connected to
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Kick {
+	pub nick: String,
+}
+
+impl Handler<Kick> for Broadcaster {
+	type Result = ();
+
+	fn handle(&mut self, msg: Kick, _: &mut Self::Context) {
+		self.announce(Notice::Kicked { nick: msg.nick });
+	}
+}
+
+/// Event that happened on another instance
+pub enum Remote {
+	Notice(Notice),
+	Message(UserMsg),
+	Edit(UserMsg),
+	Paste(models::ListedPaste),
+	/// Snapshots of every other instance
+	Peers(Vec<Snapshot>),
+	/// Events were lost while the instances were disconnected
+	Missed,
+}
+
+/// Delivers an event from another instance to the local users, returning the
+/// nicks of the users it mentions
+#[derive(Message)]
+#[rtype(result = "Vec<String>")]
+pub struct Relay(pub Remote);
+
+impl Handler<Relay> for Broadcaster {
+	type Result = MessageResult<Relay>;
+
+	fn handle(&mut self, msg: Relay, _: &mut Self::Context) -> Self::Result {
+		match msg.0 {
+			Remote::Message(user_msg) => return MessageResult(self.send(&user_msg)),
+			Remote::Edit(user_msg) => self.send_edit(&user_msg),
+			Remote::Paste(paste) => self.send_paste(paste),
+			Remote::Notice(notice) => self.apply(&notice),
+			Remote::Peers(snapshots) => {
+				let now = Instant::now();
+				for snapshot in &snapshots {
+					self.mutes.merge(snapshot, now);
+				}
+				self.peers = snapshots
+					.into_iter()
+					.flat_map(|snapshot| snapshot.users)
+					.collect();
+			}
+			Remote::Missed => {
+				for stream in self
+					.users
+					.values_mut()
+					.flat_map(|user| user.streams.iter_mut())
+				{
+					stream.lagging = true;
+					stream.resync();
+				}
+			}
+		}
+		MessageResult(Vec::new())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn room(last_event_id: u64) -> Room {
+		Room {
+			last_event_id,
+			backlog: VecDeque::new(),
+		}
+	}
+
+	fn record(room: &mut Room) -> u64 {
+		let mut event = event_data(Msg::new(MsgType::Ping));
+		room.record(&mut event);
+		event.id.unwrap()
+	}
+
+	#[test]
+	fn resumes_from_the_last_event_with_an_empty_backlog() {
+		let room = room(100);
+		assert!(room.can_resume(100));
+		assert_eq!(room.events_since(100).count(), 0);
+	}
+
+	#[test]
+	fn cant_resume_past_an_empty_backlog() {
+		let room = room(100);
+		assert!(!room.can_resume(99));
+		assert!(!room.can_resume(0));
+	}
+
+	#[test]
+	fn cant_resume_from_an_unknown_event() {
+		let mut room = room(100);
+		record(&mut room);
+		assert!(!room.can_resume(102));
+		assert!(!room.can_resume(u64::MAX));
+	}
+
+	#[test]
+	fn sends_only_the_missed_events() {
+		let mut room = room(100);
+		let ids = (0..3).map(|_| record(&mut room)).collect::<Vec<u64>>();
+		assert_eq!(ids, [101, 102, 103]);
+
+		assert!(room.can_resume(100));
+		assert!(room.can_resume(101));
+		let missed = room
+			.events_since(101)
+			.map(|event| event.id.unwrap())
+			.collect::<Vec<u64>>();
+		assert_eq!(missed, [102, 103]);
+	}
+
+	#[test]
+	fn accepts_emoji() {
+		for emoji in &[
+			"👍",
+			"❤️",
+			"👍🏽",
+			"👨‍👩‍👧‍👦",
+			"🏳️‍🌈",
+			"🇵🇱",
+			"🏴\u{E0067}\u{E0062}\u{E0073}\u{E0063}\u{E0074}\u{E007F}",
+			"1️⃣",
+			"#⃣",
+			"⭐",
+			"©️",
+		] {
+			assert!(is_valid_reaction(emoji), "{}", emoji);
+		}
+	}
+
+	#[test]
+	fn rejects_words_and_lone_modifiers() {
+		for reaction in &[
+			"",
+			"+1",
+			"ok",
+			"1",
+			"да",
+			"ñññ",
+			"é",
+			"👍 ",
+			" 👍",
+			"👍a",
+			"\u{200D}",
+			"\u{FE0F}",
+			"\u{20E3}",
+			"a\u{20E3}",
+			"👍👍👍👍👍👍👍👍👍",
+		] {
+			assert!(!is_valid_reaction(reaction), "{:?}", reaction);
+		}
+	}
+
+	#[test]
+	fn mutes_outlive_the_user() {
+		let now = Instant::now();
+		let ip = Some([10, 0, 0, 1].into());
+		let mut mutes = Mutes::default();
+		mutes.add("Alice", ip, now + Duration::from_secs(60));
+
+		let remaining = Some(Duration::from_secs(60));
+		assert_eq!(mutes.remaining("alice", None, now), remaining);
+		assert_eq!(mutes.remaining("ALICE", None, now), remaining);
+		assert_eq!(mutes.remaining("bob", ip, now), remaining);
+		assert_eq!(mutes.remaining("bob", None, now), None);
+		assert_eq!(
+			mutes.remaining("bob", Some([10, 0, 0, 2].into()), now),
+			None
+		);
+	}
+
+	#[test]
+	fn mutes_expire() {
+		let now = Instant::now();
+		let later = now + Duration::from_secs(61);
+		let mut mutes = Mutes::default();
+		mutes.add("alice", None, now + Duration::from_secs(60));
+		assert_eq!(mutes.remaining("alice", None, later), None);
+
+		mutes.prune(later);
+		assert!(mutes.nicks.is_empty());
+	}
+
+	#[test]
+	fn merges_the_longest_mutes() {
+		let now = Instant::now();
+		let ip = [10, 0, 0, 1].into();
+		let mut mutes = Mutes::default();
+		mutes.add("alice", None, now + Duration::from_secs(60));
+		mutes.merge(
+			&Snapshot {
+				users: Vec::new(),
+				muted_nicks: vec![(String::from("alice"), 30), (String::from("bob"), 30)],
+				muted_ips: vec![(ip, 90)],
+			},
+			now,
+		);
+
+		assert_eq!(
+			mutes.remaining("alice", None, now),
+			Some(Duration::from_secs(60))
+		);
+		assert_eq!(
+			mutes.remaining("bob", None, now),
+			Some(Duration::from_secs(30))
+		);
+		assert_eq!(
+			mutes.remaining("carol", Some(ip), now),
+			Some(Duration::from_secs(90))
+		);
+	}
+
+	#[test]
+	fn nicks_are_unique_across_instances() {
+		let broadcaster = Broadcaster {
+			peers: vec![Peer {
+				id: 1,
+				nick: String::from("Alice"),
+				color: None,
+				rooms: vec![String::from(DEFAULT_ROOM)],
+				ip: None,
+			}],
+			..Broadcaster::default()
+		};
+		assert!(broadcaster.is_nick_taken("alice", None));
+		assert!(broadcaster.is_nick_taken("alice", Some(2)));
+		// The same session, moving between instances
+		assert!(!broadcaster.is_nick_taken("alice", Some(1)));
+		assert!(!broadcaster.is_nick_taken("bob", None));
+	}
+
+	#[test]
+	fn forgets_the_oldest_events() {
+		let mut room = room(0);
+		for _ in 0..BACKLOG_LEN + 1 {
+			record(&mut room);
+		}
+		assert_eq!(room.backlog.len(), BACKLOG_LEN);
+		assert!(!room.can_resume(0));
+		assert!(room.can_resume(1));
+		assert_eq!(room.events_since(1).count(), BACKLOG_LEN);
+	}
 }
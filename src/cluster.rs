@@ -0,0 +1,337 @@
+//! Relays chat events between chitchat instances sharing the same database, over
+//! Postgres LISTEN/NOTIFY. Every instance owns its connected users, and shares them
+//! with the others through the `presences` table, so that nicks stay unique and
+//! `/users`, private messages, kicks and mutes reach users connected elsewhere.
+
+use std::net::IpAddr;
+use std::thread;
+use std::time::Duration;
+
+use crate::chat::{
+	self, Broadcaster, Peer, Presence, PrivateMsg, ReactionsMsg, Remote, Renamed, Typing,
+};
+use crate::get_paste::Highlighter;
+use crate::models;
+use crate::{history, mentions, presences, Pool};
+use actix::{Addr, Arbiter};
+use actix_web::error::BlockingError;
+use actix_web::web;
+use diesel::prelude::*;
+use diesel::sql_types::Text;
+use postgres::fallible_iterator::FallibleIterator;
+use postgres_native_tls::MakeTlsConnector;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+
+const CHANNEL: &str = "chitchat_events";
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Chat event published to the other instances. Messages and pastes are sent by
+/// id and loaded back from the database, since a notification can't carry more
+/// than 8000 bytes.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum Notice {
+	MessageSent(i64),
+	MessageEdited(i64),
+	MessageDeleted {
+		room: String,
+		id: i64,
+	},
+	ReactionsUpdated {
+		room: String,
+		reactions: ReactionsMsg,
+	},
+	PasteSent(i64),
+	UserJoined {
+		room: String,
+		presence: Presence,
+	},
+	UserLeft {
+		room: String,
+		presence: Presence,
+	},
+	UserRenamed {
+		room: String,
+		renamed: Renamed,
+	},
+	Typing {
+		room: String,
+		typing: Typing,
+	},
+	Muted {
+		nick: String,
+		ip: Option<IpAddr>,
+		secs: u64,
+	},
+	Kicked {
+		nick: String,
+	},
+	/// Sent to the instance of the recipient, the sender already got it
+	PrivateMessage(PrivateMsg),
+	/// An instance saved a snapshot with different users
+	PresencesChanged,
+}
+
+/// Users connected to an instance, and the mutes it knows of. Kept in the
+/// database, since it wouldn't fit in a notification.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Snapshot {
+	pub users: Vec<Peer>,
+	pub muted_nicks: Vec<(String, u64)>,
+	pub muted_ips: Vec<(IpAddr, u64)>,
+}
+
+/// What the broadcaster hands over to be shared with the other instances
+pub enum Published {
+	Notice(Notice),
+	Snapshot(Snapshot),
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+	/// Instance the notice comes from, since it's delivered to its sender too
+	node: u64,
+	notice: Notice,
+}
+
+/// Starts a broadcaster that publishes its events to the other instances, and
+/// delivers theirs to the local users.
+pub fn start(
+	database_url: String,
+	pool: Pool,
+	highlighter: web::Data<Highlighter>,
+) -> Addr<Broadcaster> {
+	let node = rand::random::<u64>();
+
+	let (published, to_publish) = mpsc::unbounded_channel();
+	let broadcaster = Broadcaster::new(Some(published));
+	Arbiter::spawn(publish(pool.clone(), node, broadcaster.clone(), to_publish));
+
+	let (received, to_relay) = mpsc::unbounded_channel();
+	thread::spawn(move || listen(&database_url, node, received));
+	Arbiter::spawn(relay(
+		pool,
+		node,
+		highlighter,
+		broadcaster.clone(),
+		to_relay,
+	));
+
+	broadcaster
+}
+
+/// Runs `query` on the thread pool, since the arbiter also runs the broadcaster.
+async fn blocking<T, F>(pool: &Pool, query: F) -> Result<T, String>
+where
+	T: Send + 'static,
+	F: FnOnce(&PgConnection) -> QueryResult<T> + Send + 'static,
+{
+	let pool = pool.clone();
+	let result = web::block(move || {
+		let db_conn = pool.get().map_err(|e| e.to_string())?;
+		query(&db_conn).map_err(|e| e.to_string())
+	})
+	.await;
+	match result {
+		Ok(result) => Ok(result),
+		Err(BlockingError::Error(e)) => Err(e),
+		Err(BlockingError::Canceled) => Err(String::from("Query canceled")),
+	}
+}
+
+/// Publishes notices, and saves snapshots. The other instances are only told
+/// about a snapshot when its users changed, since one is saved on every heartbeat.
+async fn publish(
+	pool: Pool,
+	node: u64,
+	broadcaster: Addr<Broadcaster>,
+	mut published: mpsc::UnboundedReceiver<Published>,
+) {
+	let mut shared_users = None;
+	while let Some(published) = published.recv().await {
+		let snapshot = match published {
+			Published::Notice(notice) => {
+				notify(&pool, node, notice).await;
+				continue;
+			}
+			Published::Snapshot(snapshot) => snapshot,
+		};
+
+		let changed = shared_users.as_ref() != Some(&snapshot.users);
+		let users = snapshot.users.clone();
+		let saved = blocking(&pool, move |conn| {
+			presences::save(conn, node, &snapshot)?;
+			presences::others(conn, node)
+		})
+		.await;
+		match saved {
+			Ok(others) => broadcaster.do_send(chat::Relay(Remote::Peers(others))),
+			Err(e) => {
+				println!("Failed to share the connected users: {}", e);
+				continue;
+			}
+		}
+
+		shared_users = Some(users);
+		if changed {
+			notify(&pool, node, Notice::PresencesChanged).await;
+		}
+	}
+}
+
+async fn notify(pool: &Pool, node: u64, notice: Notice) {
+	let payload = serde_json::to_string(&Envelope { node, notice }).unwrap();
+	let notified = blocking(pool, move |conn| {
+		diesel::sql_query("SELECT pg_notify($1, $2)")
+			.bind::<Text, _>(CHANNEL)
+			.bind::<Text, _>(payload)
+			.execute(conn)
+	})
+	.await;
+	if let Err(e) = notified {
+		println!("Failed to publish chat event: {}", e);
+	}
+}
+
+/// Receives the notices of the other instances on a dedicated connection, since
+/// diesel can't wait for notifications. Reconnects for as long as the server runs.
+fn listen(database_url: &str, node: u64, received: mpsc::UnboundedSender<Remote>) {
+	// Used whenever `database_url` asks for it with `sslmode`
+	let tls = match native_tls::TlsConnector::new() {
+		Ok(connector) => MakeTlsConnector::new(connector),
+		Err(e) => {
+			println!(
+				"Failed to set up TLS, events of the other chitchat instances won't be received: {}",
+				e
+			);
+			return;
+		}
+	};
+
+	let mut reconnecting = false;
+	loop {
+		match receive(database_url, tls.clone(), node, &received, reconnecting) {
+			Ok(()) => return,
+			Err(e) => println!("Lost connection to the other chitchat instances: {}", e),
+		}
+		reconnecting = true;
+		thread::sleep(RECONNECT_DELAY);
+	}
+}
+
+/// Forwards notices until the connection drops. Returns Ok once the server is
+/// shutting down.
+fn receive(
+	database_url: &str,
+	tls: MakeTlsConnector,
+	node: u64,
+	received: &mpsc::UnboundedSender<Remote>,
+	reconnecting: bool,
+) -> Result<(), postgres::Error> {
+	let mut client = postgres::Client::connect(database_url, tls)?;
+	client.batch_execute(&format!("LISTEN {}", CHANNEL))?;
+
+	// Whatever was published while disconnected is lost
+	if reconnecting && received.send(Remote::Missed).is_err() {
+		return Ok(());
+	}
+
+	let mut notifications = client.notifications();
+	let mut notifications = notifications.blocking_iter();
+	while let Some(notification) = notifications.next()? {
+		let envelope = match serde_json::from_str::<Envelope>(notification.payload()) {
+			Ok(envelope) => envelope,
+			Err(e) => {
+				println!("Invalid chat event from another instance: {}", e);
+				continue;
+			}
+		};
+		if envelope.node == node {
+			continue;
+		}
+		if received.send(Remote::Notice(envelope.notice)).is_err() {
+			return Ok(());
+		}
+	}
+	Ok(())
+}
+
+/// Loads the messages, pastes and snapshots notices refer to, and hands them to
+/// the broadcaster. Mentions of local users are saved here, like `post_msg` does.
+async fn relay(
+	pool: Pool,
+	node: u64,
+	highlighter: web::Data<Highlighter>,
+	broadcaster: Addr<Broadcaster>,
+	mut received: mpsc::UnboundedReceiver<Remote>,
+) {
+	while let Some(remote) = received.recv().await {
+		let highlighter = highlighter.clone();
+		let resolved = blocking(&pool, move |conn| resolve(conn, node, &highlighter, remote)).await;
+		let remote = match resolved {
+			Ok(Some(remote)) => remote,
+			// Already gone, e.g. deleted right after being sent
+			Ok(None) => continue,
+			Err(e) => {
+				println!("Failed to load chat event from another instance: {}", e);
+				continue;
+			}
+		};
+
+		let message_id = match &remote {
+			Remote::Message(user_msg) => Some(user_msg.id),
+			_ => None,
+		};
+		let mentioned = match broadcaster.send(chat::Relay(remote)).await {
+			Ok(mentioned) => mentioned,
+			Err(e) => {
+				println!("Failed to relay chat event: {}", e);
+				continue;
+			}
+		};
+		if let Some(message_id) = message_id {
+			if !mentioned.is_empty() {
+				let saved = blocking(&pool, move |conn| {
+					mentions::save(conn, message_id, &mentioned)
+				})
+				.await;
+				if let Err(e) = saved {
+					println!("Error saving mentions: {}", e);
+				}
+			}
+		}
+	}
+}
+
+/// Replaces notices that refer to the database with what they refer to.
+fn resolve(
+	conn: &PgConnection,
+	node: u64,
+	highlighter: &Highlighter,
+	remote: Remote,
+) -> QueryResult<Option<Remote>> {
+	let notice = match remote {
+		Remote::Notice(notice) => notice,
+		remote => return Ok(Some(remote)),
+	};
+
+	let remote = match notice {
+		Notice::MessageSent(id) => history::find(conn, id)?
+			.filter(|user_msg| !user_msg.deleted)
+			.map(Remote::Message),
+		Notice::MessageEdited(id) => history::find(conn, id)?
+			.filter(|user_msg| !user_msg.deleted)
+			.map(Remote::Edit),
+		Notice::PasteSent(id) => {
+			use crate::schema::pastes::dsl::pastes;
+			pastes
+				.find(id)
+				.first::<models::Paste>(conn)
+				.optional()?
+				.map(|paste| Remote::Paste(highlighter.listed(paste)))
+		}
+		Notice::PresencesChanged => Some(Remote::Peers(presences::others(conn, node)?)),
+		notice => Some(Remote::Notice(notice)),
+	};
+	Ok(remote)
+}
@@ -0,0 +1,335 @@
+use std::fmt;
+
+/// Background of the chat, nick colors are checked against it
+const THEME_BACKGROUND: Rgb = Rgb(0x39, 0x39, 0x39);
+/// WCAG minimum contrast ratio for large text
+const MIN_CONTRAST: f64 = 3.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rgb(u8, u8, u8);
+
+#[derive(Debug)]
+pub enum ColorError {
+	/// Not a hex color, `rgb()` or a named color
+	Unrecognized,
+	LowContrast {
+		ratio: f64,
+	},
+}
+
+impl fmt::Display for ColorError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			ColorError::Unrecognized => {
+				f.write_str("Unrecognized color, use #rgb, #rrggbb, rgb(r, g, b) or a named color")
+			}
+			ColorError::LowContrast { ratio } => write!(
+				f,
+				"Color is unreadable on the chat background (contrast {:.1}:1, at least {:.1}:1 is required)",
+				ratio, MIN_CONTRAST
+			),
+		}
+	}
+}
+
+/// Parses a user supplied color and checks it is readable. Returns the color
+/// normalized to `#rrggbb`, which is safe to put into a `style` attribute.
+pub fn validate(input: &str) -> Result<String, ColorError> {
+	let color = parse(input).ok_or(ColorError::Unrecognized)?;
+
+	let ratio = color.contrast(THEME_BACKGROUND);
+	if ratio < MIN_CONTRAST {
+		return Err(ColorError::LowContrast { ratio });
+	}
+
+	Ok(color.to_string())
+}
+
+fn parse(input: &str) -> Option<Rgb> {
+	let input = input.trim().to_ascii_lowercase();
+
+	if let Some(hex) = input.strip_prefix('#') {
+		return parse_hex(hex);
+	}
+	if let Some(args) = input
+		.strip_prefix("rgb(")
+		.and_then(|rest| rest.strip_suffix(')'))
+	{
+		return parse_rgb_args(args);
+	}
+	NAMED_COLORS
+		.iter()
+		.find(|(name, _)| *name == input)
+		.map(|(_, rgb)| *rgb)
+}
+
+fn parse_hex(hex: &str) -> Option<Rgb> {
+	if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+		return None;
+	}
+	let digit = |i: usize| u8::from_str_radix(&hex[i..=i], 16).ok();
+	let byte = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).ok();
+	match hex.len() {
+		3 => Some(Rgb(digit(0)? * 0x11, digit(1)? * 0x11, digit(2)? * 0x11)),
+		6 => Some(Rgb(byte(0)?, byte(2)?, byte(4)?)),
+		_ => None,
+	}
+}
+
+fn parse_rgb_args(args: &str) -> Option<Rgb> {
+	let channels = args
+		.split(',')
+		.map(|channel| channel.trim().parse::<u8>().ok())
+		.collect::<Option<Vec<u8>>>()?;
+	match channels.as_slice() {
+		[r, g, b] => Some(Rgb(*r, *g, *b)),
+		_ => None,
+	}
+}
+
+impl Rgb {
+	/// WCAG relative luminance
+	fn luminance(self) -> f64 {
+		let channel = |c: u8| {
+			let c = f64::from(c) / 255.0;
+			if c <= 0.03928 {
+				c / 12.92
+			} else {
+				((c + 0.055) / 1.055).powf(2.4)
+			}
+		};
+		0.2126 * channel(self.0) + 0.7152 * channel(self.1) + 0.0722 * channel(self.2)
+	}
+
+	/// WCAG contrast ratio, from 1 to 21
+	fn contrast(self, other: Rgb) -> f64 {
+		let (l1, l2) = (self.luminance(), other.luminance());
+		(l1.max(l2) + 0.05) / (l1.min(l2) + 0.05)
+	}
+}
+
+impl fmt::Display for Rgb {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "#{:02x}{:02x}{:02x}", self.0, self.1, self.2)
+	}
+}
+
+/// CSS named colors
+const NAMED_COLORS: &[(&str, Rgb)] = &[
+	("aliceblue", Rgb(240, 248, 255)),
+	("antiquewhite", Rgb(250, 235, 215)),
+	("aqua", Rgb(0, 255, 255)),
+	("aquamarine", Rgb(127, 255, 212)),
+	("azure", Rgb(240, 255, 255)),
+	("beige", Rgb(245, 245, 220)),
+	("bisque", Rgb(255, 228, 196)),
+	("black", Rgb(0, 0, 0)),
+	("blanchedalmond", Rgb(255, 235, 205)),
+	("blue", Rgb(0, 0, 255)),
+	("blueviolet", Rgb(138, 43, 226)),
+	("brown", Rgb(165, 42, 42)),
+	("burlywood", Rgb(222, 184, 135)),
+	("cadetblue", Rgb(95, 158, 160)),
+	("chartreuse", Rgb(127, 255, 0)),
+	("chocolate", Rgb(210, 105, 30)),
+	("coral", Rgb(255, 127, 80)),
+	("cornflowerblue", Rgb(100, 149, 237)),
+	("cornsilk", Rgb(255, 248, 220)),
+	("crimson", Rgb(220, 20, 60)),
+	("cyan", Rgb(0, 255, 255)),
+	("darkblue", Rgb(0, 0, 139)),
+	("darkcyan", Rgb(0, 139, 139)),
+	("darkgoldenrod", Rgb(184, 134, 11)),
+	("darkgray", Rgb(169, 169, 169)),
+	("darkgreen", Rgb(0, 100, 0)),
+	("darkgrey", Rgb(169, 169, 169)),
+	("darkkhaki", Rgb(189, 183, 107)),
+	("darkmagenta", Rgb(139, 0, 139)),
+	("darkolivegreen", Rgb(85, 107, 47)),
+	("darkorange", Rgb(255, 140, 0)),
+	("darkorchid", Rgb(153, 50, 204)),
+	("darkred", Rgb(139, 0, 0)),
+	("darksalmon", Rgb(233, 150, 122)),
+	("darkseagreen", Rgb(143, 188, 143)),
+	("darkslateblue", Rgb(72, 61, 139)),
+	("darkslategray", Rgb(47, 79, 79)),
+	("darkslategrey", Rgb(47, 79, 79)),
+	("darkturquoise", Rgb(0, 206, 209)),
+	("darkviolet", Rgb(148, 0, 211)),
+	("deeppink", Rgb(255, 20, 147)),
+	("deepskyblue", Rgb(0, 191, 255)),
+	("dimgray", Rgb(105, 105, 105)),
+	("dimgrey", Rgb(105, 105, 105)),
+	("dodgerblue", Rgb(30, 144, 255)),
+	("firebrick", Rgb(178, 34, 34)),
+	("floralwhite", Rgb(255, 250, 240)),
+	("forestgreen", Rgb(34, 139, 34)),
+	("fuchsia", Rgb(255, 0, 255)),
+	("gainsboro", Rgb(220, 220, 220)),
+	("ghostwhite", Rgb(248, 248, 255)),
+	("gold", Rgb(255, 215, 0)),
+	("goldenrod", Rgb(218, 165, 32)),
+	("gray", Rgb(128, 128, 128)),
+	("green", Rgb(0, 128, 0)),
+	("greenyellow", Rgb(173, 255, 47)),
+	("grey", Rgb(128, 128, 128)),
+	("honeydew", Rgb(240, 255, 240)),
+	("hotpink", Rgb(255, 105, 180)),
+	("indianred", Rgb(205, 92, 92)),
+	("indigo", Rgb(75, 0, 130)),
+	("ivory", Rgb(255, 255, 240)),
+	("khaki", Rgb(240, 230, 140)),
+	("lavender", Rgb(230, 230, 250)),
+	("lavenderblush", Rgb(255, 240, 245)),
+	("lawngreen", Rgb(124, 252, 0)),
+	("lemonchiffon", Rgb(255, 250, 205)),
+	("lightblue", Rgb(173, 216, 230)),
+	("lightcoral", Rgb(240, 128, 128)),
+	("lightcyan", Rgb(224, 255, 255)),
+	("lightgoldenrodyellow", Rgb(250, 250, 210)),
+	("lightgray", Rgb(211, 211, 211)),
+	("lightgreen", Rgb(144, 238, 144)),
+	("lightgrey", Rgb(211, 211, 211)),
+	("lightpink", Rgb(255, 182, 193)),
+	("lightsalmon", Rgb(255, 160, 122)),
+	("lightseagreen", Rgb(32, 178, 170)),
+	("lightskyblue", Rgb(135, 206, 250)),
+	("lightslategray", Rgb(119, 136, 153)),
+	("lightslategrey", Rgb(119, 136, 153)),
+	("lightsteelblue", Rgb(176, 196, 222)),
+	("lightyellow", Rgb(255, 255, 224)),
+	("lime", Rgb(0, 255, 0)),
+	("limegreen", Rgb(50, 205, 50)),
+	("linen", Rgb(250, 240, 230)),
+	("magenta", Rgb(255, 0, 255)),
+	("maroon", Rgb(128, 0, 0)),
+	("mediumaquamarine", Rgb(102, 205, 170)),
+	("mediumblue", Rgb(0, 0, 205)),
+	("mediumorchid", Rgb(186, 85, 211)),
+	("mediumpurple", Rgb(147, 112, 219)),
+	("mediumseagreen", Rgb(60, 179, 113)),
+	("mediumslateblue", Rgb(123, 104, 238)),
+	("mediumspringgreen", Rgb(0, 250, 154)),
+	("mediumturquoise", Rgb(72, 209, 204)),
+	("mediumvioletred", Rgb(199, 21, 133)),
+	("midnightblue", Rgb(25, 25, 112)),
+	("mintcream", Rgb(245, 255, 250)),
+	("mistyrose", Rgb(255, 228, 225)),
+	("moccasin", Rgb(255, 228, 181)),
+	("navajowhite", Rgb(255, 222, 173)),
+	("navy", Rgb(0, 0, 128)),
+	("oldlace", Rgb(253, 245, 230)),
+	("olive", Rgb(128, 128, 0)),
+	("olivedrab", Rgb(107, 142, 35)),
+	("orange", Rgb(255, 165, 0)),
+	("orangered", Rgb(255, 69, 0)),
+	("orchid", Rgb(218, 112, 214)),
+	("palegoldenrod", Rgb(238, 232, 170)),
+	("palegreen", Rgb(152, 251, 152)),
+	("paleturquoise", Rgb(175, 238, 238)),
+	("palevioletred", Rgb(219, 112, 147)),
+	("papayawhip", Rgb(255, 239, 213)),
+	("peachpuff", Rgb(255, 218, 185)),
+	("peru", Rgb(205, 133, 63)),
+	("pink", Rgb(255, 192, 203)),
+	("plum", Rgb(221, 160, 221)),
+	("powderblue", Rgb(176, 224, 230)),
+	("purple", Rgb(128, 0, 128)),
+	("rebeccapurple", Rgb(102, 51, 153)),
+	("red", Rgb(255, 0, 0)),
+	("rosybrown", Rgb(188, 143, 143)),
+	("royalblue", Rgb(65, 105, 225)),
+	("saddlebrown", Rgb(139, 69, 19)),
+	("salmon", Rgb(250, 128, 114)),
+	("sandybrown", Rgb(244, 164, 96)),
+	("seagreen", Rgb(46, 139, 87)),
+	("seashell", Rgb(255, 245, 238)),
+	("sienna", Rgb(160, 82, 45)),
+	("silver", Rgb(192, 192, 192)),
+	("skyblue", Rgb(135, 206, 235)),
+	("slateblue", Rgb(106, 90, 205)),
+	("slategray", Rgb(112, 128, 144)),
+	("slategrey", Rgb(112, 128, 144)),
+	("snow", Rgb(255, 250, 250)),
+	("springgreen", Rgb(0, 255, 127)),
+	("steelblue", Rgb(70, 130, 180)),
+	("tan", Rgb(210, 180, 140)),
+	("teal", Rgb(0, 128, 128)),
+	("thistle", Rgb(216, 191, 216)),
+	("tomato", Rgb(255, 99, 71)),
+	("turquoise", Rgb(64, 224, 208)),
+	("violet", Rgb(238, 130, 238)),
+	("wheat", Rgb(245, 222, 179)),
+	("white", Rgb(255, 255, 255)),
+	("whitesmoke", Rgb(245, 245, 245)),
+	("yellow", Rgb(255, 255, 0)),
+	("yellowgreen", Rgb(154, 205, 50)),
+];
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn expands_short_hex() {
+		assert_eq!(parse_hex("abc"), Some(Rgb(0xaa, 0xbb, 0xcc)));
+		assert_eq!(validate("#ABC").unwrap(), "#aabbcc");
+	}
+
+	#[test]
+	fn parses_long_hex() {
+		assert_eq!(parse_hex("ff8000"), Some(Rgb(0xff, 0x80, 0x00)));
+	}
+
+	#[test]
+	fn rejects_malformed_hex() {
+		assert_eq!(parse_hex(""), None);
+		assert_eq!(parse_hex("abcd"), None);
+		assert_eq!(parse_hex("ggg"), None);
+		assert_eq!(parse_hex("+12345"), None);
+		assert_eq!(parse_hex("ééé"), None);
+	}
+
+	#[test]
+	fn parses_rgb() {
+		assert_eq!(parse("rgb(255, 128, 0)"), Some(Rgb(255, 128, 0)));
+		assert_eq!(parse(" RGB(1,2,3) "), Some(Rgb(1, 2, 3)));
+	}
+
+	#[test]
+	fn rejects_out_of_range_rgb() {
+		assert_eq!(parse("rgb(256,0,0)"), None);
+		assert_eq!(parse("rgb(-1,0,0)"), None);
+		assert_eq!(parse("rgb(1,2)"), None);
+		assert_eq!(parse("rgb(1,2,3,4)"), None);
+	}
+
+	#[test]
+	fn parses_named_colors() {
+		assert_eq!(parse("Gold"), Some(Rgb(255, 215, 0)));
+		assert_eq!(parse("notacolor"), None);
+	}
+
+	#[test]
+	fn contrast_bounds() {
+		let black = Rgb(0, 0, 0);
+		let white = Rgb(255, 255, 255);
+		assert!((black.contrast(white) - 21.0).abs() < 1e-9);
+		assert!((white.contrast(black) - 21.0).abs() < 1e-9);
+		assert!((white.contrast(white) - 1.0).abs() < 1e-9);
+	}
+
+	#[test]
+	fn rejects_unreadable_colors() {
+		match validate("#393939") {
+			Err(ColorError::LowContrast { ratio }) => assert!(ratio < MIN_CONTRAST),
+			other => panic!("expected low contrast, got {:?}", other),
+		}
+		assert!(matches!(
+			validate("black"),
+			Err(ColorError::LowContrast { .. })
+		));
+		assert!(matches!(validate("nope"), Err(ColorError::Unrecognized)));
+		assert_eq!(validate("white").unwrap(), "#ffffff");
+	}
+}
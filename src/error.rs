@@ -0,0 +1,156 @@
+use crate::color::ColorError;
+use crate::get_paste::{Arg, PasteRenderer};
+use actix_web::dev::HttpResponseBuilder;
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use serde::Serialize;
+use std::fmt;
+
+/// Failure of a chat operation, shared by the HTTP handlers and the WebSocket transport.
+#[derive(Debug)]
+pub enum ChatError {
+	Unauthorized,
+	Forbidden(String),
+	NotFound,
+	/// Existed, but expired or was used up
+	Gone,
+	Conflict(String),
+	BadRequest(String),
+	InvalidColor(ColorError),
+	/// Seconds until the client may try again
+	RateLimited(u64),
+	/// The database failed, the request may go through later
+	Unavailable,
+	Internal,
+}
+
+/// JSON body of an error response, also used as the data of `Error` events
+#[derive(Serialize)]
+pub struct ErrorBody {
+	pub error: &'static str,
+	pub message: String,
+}
+
+impl ChatError {
+	fn kind(&self) -> &'static str {
+		match self {
+			ChatError::Unauthorized => "Unauthorized",
+			ChatError::Forbidden(_) => "Forbidden",
+			ChatError::NotFound => "NotFound",
+			ChatError::Gone => "Gone",
+			ChatError::Conflict(_) => "Conflict",
+			ChatError::BadRequest(_) => "BadRequest",
+			ChatError::InvalidColor(_) => "InvalidColor",
+			ChatError::RateLimited(_) => "RateLimited",
+			ChatError::Unavailable => "Unavailable",
+			ChatError::Internal => "Internal",
+		}
+	}
+
+	pub fn body(&self) -> ErrorBody {
+		ErrorBody {
+			error: self.kind(),
+			message: self.to_string(),
+		}
+	}
+}
+
+impl fmt::Display for ChatError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			ChatError::Unauthorized => f.write_str("Unauthorized"),
+			ChatError::Forbidden(msg) => f.write_str(msg),
+			ChatError::NotFound => f.write_str("Not found"),
+			ChatError::Gone => f.write_str("No longer available"),
+			ChatError::Conflict(msg) => f.write_str(msg),
+			ChatError::BadRequest(msg) => f.write_str(msg),
+			ChatError::InvalidColor(e) => e.fmt(f),
+			ChatError::RateLimited(secs) => write!(f, "Slow down, try again in {}s", secs),
+			ChatError::Unavailable => f.write_str("Service unavailable, try again later"),
+			ChatError::Internal => f.write_str("Internal server error"),
+		}
+	}
+}
+
+impl From<ColorError> for ChatError {
+	fn from(e: ColorError) -> Self {
+		ChatError::InvalidColor(e)
+	}
+}
+
+impl ResponseError for ChatError {
+	fn status_code(&self) -> StatusCode {
+		match self {
+			ChatError::Unauthorized => StatusCode::UNAUTHORIZED,
+			ChatError::Forbidden(_) => StatusCode::FORBIDDEN,
+			ChatError::NotFound => StatusCode::NOT_FOUND,
+			ChatError::Gone => StatusCode::GONE,
+			ChatError::Conflict(_) => StatusCode::CONFLICT,
+			ChatError::BadRequest(_) | ChatError::InvalidColor(_) => StatusCode::BAD_REQUEST,
+			ChatError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+			ChatError::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+			ChatError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+		}
+	}
+
+	fn error_response(&self) -> HttpResponse {
+		self.response().json(self.body())
+	}
+}
+
+impl ChatError {
+	/// Response with the status and headers of the error, but no body yet
+	fn response(&self) -> HttpResponseBuilder {
+		let mut response = HttpResponse::build(self.status_code());
+		if let ChatError::RateLimited(secs) = self {
+			response.header("Retry-After", secs.to_string());
+		}
+		response
+	}
+}
+
+/// `ChatError` of a page meant for browsers, shown as an HTML page rendered
+/// through the paste template instead of JSON
+#[derive(Debug)]
+pub struct PageError(pub ChatError);
+
+impl From<ChatError> for PageError {
+	fn from(e: ChatError) -> Self {
+		PageError(e)
+	}
+}
+
+impl fmt::Display for PageError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		self.0.fmt(f)
+	}
+}
+
+impl ResponseError for PageError {
+	fn status_code(&self) -> StatusCode {
+		self.0.status_code()
+	}
+
+	fn error_response(&self) -> HttpResponse {
+		let status = self.status_code();
+		let title = format!(
+			"{} {}",
+			status.as_u16(),
+			status.canonical_reason().unwrap_or_default()
+		);
+		match PasteRenderer::new(&[
+			Arg::Text(title.into_bytes()),
+			Arg::Text(self.0.to_string().into_bytes()),
+		]) {
+			Ok(page) => self
+				.0
+				.response()
+				.content_type("text/html charset=UTF-8")
+				.streaming(page),
+			Err(e) => {
+				println!("Failed to render error page: {}", e);
+				self.0.error_response()
+			}
+		}
+	}
+}
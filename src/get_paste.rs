@@ -1,10 +1,36 @@
+use crate::models::{ListedPaste, Paste};
 use actix_web::web::Bytes;
 use futures_util::task::{Context, Poll};
+use std::collections::{HashMap, VecDeque};
 use std::io::Read;
+use std::path::Path;
+use std::sync::Mutex;
+use syntect::easy::HighlightLines;
+use syntect::highlighting::{Theme, ThemeSet};
+use syntect::html::{styled_line_to_highlighted_html, IncludeBackground};
+use syntect::parsing::SyntaxSet;
+use syntect::util::LinesWithEndings;
 use tokio::macros::support::Pin;
 use tokio::stream::Stream;
 
 const TEMPLATE_PATH: &str = "src/raw.html";
+/// Asks for the password of a locked paste
+const LOCKED_TEMPLATE_PATH: &str = "src/locked.html";
+/// Matches the colors of `TEMPLATE_PATH`
+const THEME: &str = "base16-eighties.dark";
+/// Longer pastes aren't highlighted, since it takes too long
+const MAX_HIGHLIGHTED_LEN: usize = 256 * 1024;
+/// How many bytes of highlighted pastes are kept in memory
+const CACHE_CAPACITY: usize = 32 * 1024 * 1024;
+
+/// Value of a `\!` placeholder in a template
+#[derive(Clone, Debug)]
+pub enum Arg {
+	/// Escaped when inserted
+	Text(Vec<u8>),
+	/// Inserted as is
+	Html(Bytes),
+}
 
 pub struct PasteRenderer {
 	pieces: Vec<Piece>,
@@ -14,12 +40,21 @@ pub struct PasteRenderer {
 #[derive(Debug)]
 enum Piece {
 	Template(Vec<u8>),
-	Arg(Vec<u8>),
+	Arg(Arg),
 }
 
 impl PasteRenderer {
-	pub fn new(args: &[Vec<u8>]) -> std::io::Result<Self> {
-		let mut template_data = std::fs::File::open(TEMPLATE_PATH)?;
+	pub fn new(args: &[Arg]) -> std::io::Result<Self> {
+		Self::from_template(TEMPLATE_PATH, args)
+	}
+
+	/// Password form of a locked paste, with `args` holding the message shown above it
+	pub fn locked(args: &[Arg]) -> std::io::Result<Self> {
+		Self::from_template(LOCKED_TEMPLATE_PATH, args)
+	}
+
+	fn from_template(path: &str, args: &[Arg]) -> std::io::Result<Self> {
+		let mut template_data = std::fs::File::open(path)?;
 		let mut buf = vec![0u8; 1024 * 16];
 
 		let mut pieces = Vec::new();
@@ -58,20 +93,169 @@ impl Stream for PasteRenderer {
 	fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
 		if self.idx != self.pieces.len() {
 			self.idx += 1;
-			Poll::Ready(Some(Ok(Bytes::from(match &self.pieces[self.idx - 1] {
-				Piece::Template(buf) => buf.clone(),
-				Piece::Arg(buf) => format!(
-					"{}",
-					v_htmlescape::escape(std::str::from_utf8(buf).unwrap())
-				)
-				.into_bytes(),
-			}))))
+			Poll::Ready(Some(Ok(match &self.pieces[self.idx - 1] {
+				Piece::Template(buf) => Bytes::from(buf.clone()),
+				Piece::Arg(Arg::Text(buf)) => Bytes::from(
+					format!(
+						"{}",
+						v_htmlescape::escape(std::str::from_utf8(buf).unwrap())
+					)
+					.into_bytes(),
+				),
+				Piece::Arg(Arg::Html(html)) => html.clone(),
+			})))
 		} else {
 			Poll::Ready(None)
 		}
 	}
 
 	fn size_hint(&self) -> (usize, Option<usize>) {
-		unimplemented!()
+		let remaining = self.pieces.len() - self.idx;
+		(remaining, Some(remaining))
+	}
+}
+
+/// Highlights pastes into HTML with inline styles, so that paste pages need
+/// neither JavaScript nor stylesheets from elsewhere.
+pub struct Highlighter {
+	syntaxes: SyntaxSet,
+	theme: Theme,
+	cache: Mutex<Cache>,
+}
+
+/// Highlighted pastes by id. Pastes never change, so entries never go stale,
+/// and the oldest ones are evicted first.
+#[derive(Default)]
+struct Cache {
+	entries: HashMap<i64, Bytes>,
+	order: VecDeque<i64>,
+	len: usize,
+}
+
+impl Cache {
+	fn get(&self, id: i64) -> Option<Bytes> {
+		self.entries.get(&id).cloned()
+	}
+
+	fn insert(&mut self, id: i64, html: Bytes) {
+		if html.len() > CACHE_CAPACITY || self.entries.contains_key(&id) {
+			return;
+		}
+		while self.len + html.len() > CACHE_CAPACITY {
+			let evicted = match self.order.pop_front() {
+				Some(evicted) => evicted,
+				None => break,
+			};
+			if let Some(evicted) = self.entries.remove(&evicted) {
+				self.len -= evicted.len();
+			}
+		}
+		self.len += html.len();
+		self.order.push_back(id);
+		self.entries.insert(id, html);
+	}
+}
+
+impl Highlighter {
+	pub fn new() -> Self {
+		let mut themes = ThemeSet::load_defaults();
+		Highlighter {
+			syntaxes: SyntaxSet::load_defaults_newlines(),
+			theme: themes
+				.themes
+				.remove(THEME)
+				.expect("Missing highlighting theme"),
+			cache: Mutex::new(Cache::default()),
+		}
+	}
+
+	/// Highlighted content of `paste`. Pastes that expire, have a view limit or a
+	/// password aren't cached, so that their content doesn't outlive them. Neither
+	/// are the ones too long to be highlighted, which are only escaped.
+	pub fn highlight(&self, paste: &Paste) -> Bytes {
+		let cacheable = paste.expires_at.is_none()
+			&& paste.max_views.is_none()
+			&& paste.password_hash.is_none();
+		if cacheable {
+			if let Some(html) = self.cache.lock().unwrap().get(paste.id) {
+				return html;
+			}
+		}
+
+		let content = paste.content.as_deref().unwrap_or_default();
+		match self.highlight_content(paste.filename.as_deref().unwrap_or_default(), content) {
+			Some(html) => {
+				let html = Bytes::from(html);
+				if cacheable {
+					self.cache.lock().unwrap().insert(paste.id, html.clone());
+				}
+				html
+			}
+			None => Bytes::from(v_htmlescape::escape(content).to_string()),
+		}
+	}
+
+	/// `paste` as listed, with the content it still shows highlighted
+	pub fn listed(&self, paste: Paste) -> ListedPaste {
+		let paste = paste.listed();
+		let rendered = paste
+			.content
+			.as_ref()
+			.map(|_| String::from_utf8_lossy(&self.highlight(&paste)).into_owned());
+		ListedPaste { paste, rendered }
+	}
+
+	/// Picks the syntax from the file extension, or else the first line.
+	/// Returns None if the content is too long or can't be highlighted.
+	fn highlight_content(&self, filename: &str, content: &str) -> Option<String> {
+		if content.len() > MAX_HIGHLIGHTED_LEN {
+			return None;
+		}
+		let syntax = Path::new(filename)
+			.extension()
+			.and_then(|ext| ext.to_str())
+			.and_then(|ext| self.syntaxes.find_syntax_by_extension(ext))
+			.or_else(|| self.syntaxes.find_syntax_by_first_line(content))
+			.unwrap_or_else(|| self.syntaxes.find_syntax_plain_text());
+
+		let mut highlighter = HighlightLines::new(syntax, &self.theme);
+		let mut html = String::with_capacity(content.len() * 2);
+		for line in LinesWithEndings::from(content) {
+			let regions = highlighter.highlight_line(line, &self.syntaxes).ok()?;
+			html += &styled_line_to_highlighted_html(&regions, IncludeBackground::No).ok()?;
+		}
+		Some(html)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn html(len: usize) -> Bytes {
+		Bytes::from(vec![b'x'; len])
+	}
+
+	#[test]
+	fn evicts_the_oldest_pastes_past_the_capacity() {
+		let mut cache = Cache::default();
+		cache.insert(1, html(CACHE_CAPACITY / 2));
+		cache.insert(2, html(CACHE_CAPACITY / 2));
+		assert!(cache.get(1).is_some());
+
+		cache.insert(3, html(1));
+		assert!(cache.get(1).is_none());
+		assert!(cache.get(2).is_some());
+		assert!(cache.get(3).is_some());
+		assert!(cache.len <= CACHE_CAPACITY);
+	}
+
+	#[test]
+	fn skips_pastes_bigger_than_the_cache() {
+		let mut cache = Cache::default();
+		cache.insert(1, html(1));
+		cache.insert(2, html(CACHE_CAPACITY + 1));
+		assert!(cache.get(1).is_some());
+		assert!(cache.get(2).is_none());
 	}
 }
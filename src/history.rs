@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
+
+use crate::models::{HistoryEntry, NewUserMsg, Reaction, Reactions, Thread, UserMsg};
+use crate::Pool;
+use chrono::Utc;
+use diesel::prelude::*;
+use serde::{de, Deserialize, Deserializer};
+
+/// How many of the most recent messages are sent to a client when it connects.
+const CONNECTED_HISTORY_LEN: i64 = 1000;
+
+/// How often expired messages are looked for
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long messages are kept by default
+const DEFAULT_MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// How much history is kept. Both limits apply at once. Keeping everything has
+/// to be asked for with `keep_forever`.
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Retention {
+	/// Messages older than this are removed, e.g. "24h"
+	#[serde(deserialize_with = "deserialize_max_age")]
+	pub max_age: Option<Duration>,
+	/// How many of its most recent messages each room keeps
+	pub max_count: Option<u32>,
+	/// Never removes any message, whatever the limits above say
+	pub keep_forever: bool,
+}
+
+impl Default for Retention {
+	fn default() -> Self {
+		Retention {
+			max_age: Some(DEFAULT_MAX_AGE),
+			max_count: None,
+			keep_forever: false,
+		}
+	}
+}
+
+impl Retention {
+	fn is_enabled(&self) -> bool {
+		!self.keep_forever && (self.max_age.is_some() || self.max_count.is_some())
+	}
+}
+
+fn deserialize_max_age<'de, D: Deserializer<'de>>(
+	deserializer: D,
+) -> Result<Option<Duration>, D::Error> {
+	let input = String::deserialize(deserializer)?;
+	crate::parse_duration(&input)
+		.map(Some)
+		.ok_or_else(|| de::Error::custom(format!("invalid duration: {}", input)))
+}
+
+pub fn insert(conn: &PgConnection, msg: NewUserMsg) -> QueryResult<UserMsg> {
+	use crate::schema::messages::dsl::messages;
+
+	diesel::insert_into(messages)
+		.values(msg)
+		.get_result::<UserMsg>(conn)
+}
+
+/// Finds a message by id, including deleted ones.
+pub fn find(conn: &PgConnection, msg_id: i64) -> QueryResult<Option<UserMsg>> {
+	use crate::schema::messages::dsl::*;
+
+	messages.find(msg_id).first::<UserMsg>(conn).optional()
+}
+
+pub fn edit(conn: &PgConnection, msg_id: i64, new_msg: &str) -> QueryResult<UserMsg> {
+	use crate::schema::messages::dsl::*;
+
+	diesel::update(messages.find(msg_id))
+		.set((msg.eq(new_msg), edited_at.eq(Utc::now())))
+		.get_result::<UserMsg>(conn)
+}
+
+/// Wipes the content and reactions of a message, leaving a tombstone behind.
+pub fn delete(conn: &PgConnection, msg_id: i64) -> QueryResult<UserMsg> {
+	use crate::schema::messages::dsl::*;
+	use crate::schema::reactions::dsl::{message_id, reactions};
+
+	conn.transaction(|| {
+		diesel::delete(reactions.filter(message_id.eq(msg_id))).execute(conn)?;
+		diesel::update(messages.find(msg_id))
+			.set((msg.eq(""), deleted.eq(true)))
+			.get_result::<UserMsg>(conn)
+	})
+}
+
+/// Loads the recent history of `room_name`, oldest message first.
+pub fn load(conn: &PgConnection, room_name: &str) -> QueryResult<Vec<HistoryEntry>> {
+	use crate::schema::messages::dsl::*;
+
+	let mut history = messages
+		.filter(room.eq(room_name))
+		.filter(deleted.eq(false))
+		.order(id.desc())
+		.limit(CONNECTED_HISTORY_LEN)
+		.load::<UserMsg>(conn)?;
+	history.reverse();
+
+	with_details(conn, history)
+}
+
+/// Loads a thread started by the message `root_id`, replies sorted oldest first.
+/// Returns `None` if there's no such message.
+pub fn load_thread(conn: &PgConnection, root_id: i64) -> QueryResult<Option<Thread>> {
+	use crate::schema::messages::dsl::*;
+
+	let root = match find(conn, root_id)? {
+		Some(root) if !root.deleted => root,
+		_ => return Ok(None),
+	};
+	let thread_replies = messages
+		.filter(reply_to.eq(root_id))
+		.filter(deleted.eq(false))
+		.order(id.asc())
+		.load::<UserMsg>(conn)?;
+
+	let mut root = with_details(conn, vec![root])?;
+	Ok(Some(Thread {
+		root: root.remove(0),
+		replies: with_details(conn, thread_replies)?,
+	}))
+}
+
+/// Attaches the reactions and reply counts to each message.
+fn with_details(conn: &PgConnection, msgs: Vec<UserMsg>) -> QueryResult<Vec<HistoryEntry>> {
+	let ids = msgs
+		.iter()
+		.map(|user_msg| user_msg.id)
+		.collect::<Vec<i64>>();
+	let mut reactions = load_reactions(conn, &ids)?;
+	let mut replies = count_replies(conn, &ids)?;
+	Ok(msgs
+		.into_iter()
+		.map(|user_msg| HistoryEntry {
+			reactions: reactions.remove(&user_msg.id).unwrap_or_default(),
+			replies: replies.remove(&user_msg.id).unwrap_or_default(),
+			msg: user_msg,
+		})
+		.collect())
+}
+
+/// Counts the replies to each of the given messages.
+fn count_replies(conn: &PgConnection, ids: &[i64]) -> QueryResult<HashMap<i64, i64>> {
+	use crate::schema::messages::dsl::*;
+
+	let rows = messages
+		.select(reply_to)
+		.filter(reply_to.eq_any(ids))
+		.filter(deleted.eq(false))
+		.load::<Option<i64>>(conn)?;
+
+	let mut counts = HashMap::new();
+	for root_id in rows.into_iter().flatten() {
+		*counts.entry(root_id).or_insert(0) += 1;
+	}
+	Ok(counts)
+}
+
+/// Counts the reactions to each of the given messages.
+fn load_reactions(conn: &PgConnection, ids: &[i64]) -> QueryResult<HashMap<i64, Reactions>> {
+	use crate::schema::reactions::dsl::*;
+
+	let rows = reactions
+		.select((message_id, emoji))
+		.filter(message_id.eq_any(ids))
+		.load::<(i64, String)>(conn)?;
+
+	let mut counts = HashMap::<i64, Reactions>::new();
+	for (msg_id, reaction) in rows {
+		*counts
+			.entry(msg_id)
+			.or_default()
+			.entry(reaction)
+			.or_insert(0) += 1;
+	}
+	Ok(counts)
+}
+
+/// Adds the reaction of a user to a message, or takes it back if it was already
+/// there. Returns the updated reaction counts of the message.
+pub fn toggle_reaction(conn: &PgConnection, reaction: Reaction) -> QueryResult<Reactions> {
+	use crate::schema::reactions::dsl::*;
+
+	conn.transaction(|| {
+		let removed = diesel::delete(reactions.find((
+			reaction.message_id,
+			reaction.user_id,
+			&reaction.emoji,
+		)))
+		.execute(conn)?;
+		if removed == 0 {
+			diesel::insert_into(reactions)
+				.values(&reaction)
+				.execute(conn)?;
+		}
+
+		let mut counts = load_reactions(conn, &[reaction.message_id])?;
+		Ok(counts.remove(&reaction.message_id).unwrap_or_default())
+	})
+}
+
+pub fn start_cleaner(pool: Pool, retention: Retention) {
+	if retention.is_enabled() {
+		// On its own thread, as diesel blocks
+		thread::spawn(move || cleaner(pool, retention));
+	}
+}
+
+/// Removes expired messages every `CLEANUP_INTERVAL`.
+fn cleaner(pool: Pool, retention: Retention) {
+	loop {
+		thread::sleep(CLEANUP_INTERVAL);
+
+		let db_conn = match pool.get() {
+			Ok(conn) => conn,
+			Err(e) => {
+				println!("Failed to get connection to the database: {}", e);
+				continue;
+			}
+		};
+		let cleared = db_conn.transaction(|| {
+			let ids = expired(&db_conn, retention)?;
+			remove(&db_conn, &ids)
+		});
+		if let Err(e) = cleared {
+			println!("Failed to clear chat history: {}", e);
+		}
+	}
+}
+
+/// Ids of the messages `retention` says to remove.
+fn expired(conn: &PgConnection, retention: Retention) -> QueryResult<Vec<i64>> {
+	use crate::schema::messages::dsl::*;
+
+	let mut ids = Vec::new();
+	if let Some(oldest) = retention
+		.max_age
+		.and_then(|max_age| chrono::Duration::from_std(max_age).ok())
+		.and_then(|max_age| Utc::now().checked_sub_signed(max_age))
+	{
+		ids.extend(
+			messages
+				.select(id)
+				.filter(time.lt(oldest))
+				.load::<i64>(conn)?,
+		);
+	}
+
+	if let Some(max_count) = retention.max_count {
+		let rooms = messages.select(room).distinct().load::<String>(conn)?;
+		for room_name in rooms {
+			// Newest message past the limit. Tombstones don't count towards it, but
+			// go along with the messages around them.
+			let newest_expired = messages
+				.select(id)
+				.filter(room.eq(&room_name))
+				.filter(deleted.eq(false))
+				.order(id.desc())
+				.offset(i64::from(max_count))
+				.first::<i64>(conn)
+				.optional()?;
+			if let Some(newest_expired) = newest_expired {
+				ids.extend(
+					messages
+						.select(id)
+						.filter(room.eq(&room_name))
+						.filter(id.le(newest_expired))
+						.load::<i64>(conn)?,
+				);
+			}
+		}
+	}
+
+	ids.sort_unstable();
+	ids.dedup();
+	Ok(ids)
+}
+
+/// Removes messages along with their mentions and reactions. Replies outlive
+/// the message they reply to, and become regular messages.
+fn remove(conn: &PgConnection, ids: &[i64]) -> QueryResult<()> {
+	use crate::schema::{mentions, messages, reactions};
+
+	if ids.is_empty() {
+		return Ok(());
+	}
+	diesel::delete(mentions::table.filter(mentions::message_id.eq_any(ids))).execute(conn)?;
+	diesel::delete(reactions::table.filter(reactions::message_id.eq_any(ids))).execute(conn)?;
+	diesel::update(messages::table.filter(messages::reply_to.eq_any(ids)))
+		.set(messages::reply_to.eq(None::<i64>))
+		.execute(conn)?;
+	diesel::delete(messages::table.filter(messages::id.eq_any(ids))).execute(conn)?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn retention(toml: &str) -> Retention {
+		toml::from_str(toml).unwrap()
+	}
+
+	#[test]
+	fn removes_old_messages_by_default() {
+		let default = retention("");
+		assert_eq!(default.max_age, Some(DEFAULT_MAX_AGE));
+		assert!(default.is_enabled());
+
+		let by_count = retention("max_count = 100");
+		assert_eq!(by_count.max_age, Some(DEFAULT_MAX_AGE));
+		assert_eq!(by_count.max_count, Some(100));
+	}
+
+	#[test]
+	fn keeps_everything_when_asked() {
+		assert!(!retention("keep_forever = true").is_enabled());
+		assert!(!retention("keep_forever = true\nmax_age = \"1d\"").is_enabled());
+	}
+}
@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+/// Buckets are pruned once there are more than this many of them.
+const MAX_IDLE_BUCKETS: usize = 1024;
+
+/// How many actions can be done at once, and how fast that allowance refills
+#[derive(Deserialize, Clone, Copy, Debug)]
+pub struct Limit {
+	pub burst: u32,
+	pub per_minute: u32,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(default)]
+pub struct RateLimits {
+	pub messages: Limit,
+	/// Shared by every session behind an address, which can be a whole network
+	pub messages_per_ip: Limit,
+	pub pastes: Limit,
+	pub pastes_per_ip: Limit,
+	/// Password attempts on locked pastes and secrets of reserved nicks
+	pub unlocks: Limit,
+}
+
+impl Default for RateLimits {
+	fn default() -> Self {
+		RateLimits {
+			messages: Limit {
+				burst: 10,
+				per_minute: 30,
+			},
+			messages_per_ip: Limit {
+				burst: 30,
+				per_minute: 90,
+			},
+			pastes: Limit {
+				burst: 3,
+				per_minute: 5,
+			},
+			pastes_per_ip: Limit {
+				burst: 10,
+				per_minute: 15,
+			},
+			unlocks: Limit {
+				burst: 5,
+				per_minute: 5,
+			},
+		}
+	}
+}
+
+/// Who a bucket belongs to. Clients without a session fall back to their IP.
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+pub enum Key {
+	Session(u64),
+	Ip(IpAddr),
+}
+
+struct Bucket {
+	tokens: f64,
+	updated: Instant,
+}
+
+/// Token bucket rate limiter
+pub struct RateLimiter {
+	limit: Limit,
+	ip_limit: Limit,
+	buckets: Mutex<HashMap<Key, Bucket>>,
+}
+
+impl RateLimiter {
+	/// Buckets of sessions get `limit`, and buckets of addresses `ip_limit`.
+	pub fn new(limit: Limit, ip_limit: Limit) -> Self {
+		RateLimiter {
+			limit,
+			ip_limit,
+			buckets: Mutex::new(HashMap::new()),
+		}
+	}
+
+	fn limit_of(&self, key: &Key) -> Limit {
+		match key {
+			Key::Session(_) => self.limit,
+			Key::Ip(_) => self.ip_limit,
+		}
+	}
+
+	/// Takes a token from the bucket of `key`.
+	/// If it's empty, returns how long until the next token is available.
+	pub fn check(&self, key: Key) -> Result<(), Duration> {
+		self.check_at(key, Instant::now())
+	}
+
+	fn check_at(&self, key: Key, now: Instant) -> Result<(), Duration> {
+		let mut buckets = self.buckets.lock().unwrap();
+		if buckets.len() > MAX_IDLE_BUCKETS {
+			buckets.retain(|key, bucket| {
+				let limit = self.limit_of(key);
+				let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+				bucket.tokens + elapsed * f64::from(limit.per_minute) / 60.0
+					< f64::from(limit.burst)
+			});
+		}
+
+		let limit = self.limit_of(&key);
+		let burst = f64::from(limit.burst);
+		let per_sec = f64::from(limit.per_minute) / 60.0;
+
+		let bucket = buckets.entry(key).or_insert(Bucket {
+			tokens: burst,
+			updated: now,
+		});
+		let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+		bucket.tokens = (bucket.tokens + elapsed * per_sec).min(burst);
+		bucket.updated = now;
+
+		if bucket.tokens >= 1.0 {
+			bucket.tokens -= 1.0;
+			Ok(())
+		} else if per_sec > 0.0 {
+			Err(Duration::from_secs_f64((1.0 - bucket.tokens) / per_sec))
+		} else {
+			Err(Duration::from_secs(u64::MAX))
+		}
+	}
+}
+
+/// Rate limiters of the actions that get fanned out to every user, or that could
+/// be used to guess secrets
+pub struct Limiters {
+	pub messages: RateLimiter,
+	pub pastes: RateLimiter,
+	pub unlocks: RateLimiter,
+}
+
+impl Limiters {
+	pub fn new(limits: RateLimits) -> Self {
+		Limiters {
+			messages: RateLimiter::new(limits.messages, limits.messages_per_ip),
+			pastes: RateLimiter::new(limits.pastes, limits.pastes_per_ip),
+			unlocks: RateLimiter::new(limits.unlocks, limits.unlocks),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn limiter(burst: u32, per_minute: u32) -> RateLimiter {
+		let limit = Limit { burst, per_minute };
+		RateLimiter::new(limit, limit)
+	}
+
+	const KEY: Key = Key::Session(1);
+
+	#[test]
+	fn allows_a_burst() {
+		let limiter = limiter(3, 30);
+		let now = Instant::now();
+		for _ in 0..3 {
+			assert_eq!(limiter.check_at(KEY.clone(), now), Ok(()));
+		}
+		assert_eq!(
+			limiter.check_at(KEY.clone(), now),
+			Err(Duration::from_secs(2))
+		);
+	}
+
+	#[test]
+	fn refills_over_time() {
+		let limiter = limiter(1, 30);
+		let now = Instant::now();
+		assert!(limiter.check_at(KEY.clone(), now).is_ok());
+
+		let retry_after = limiter
+			.check_at(KEY.clone(), now + Duration::from_secs(1))
+			.unwrap_err();
+		assert!((retry_after.as_secs_f64() - 1.0).abs() < 1e-6);
+		assert!(limiter
+			.check_at(KEY.clone(), now + Duration::from_secs(2))
+			.is_ok());
+	}
+
+	#[test]
+	fn never_refills_past_the_burst() {
+		let limiter = limiter(2, 60);
+		let now = Instant::now();
+		let later = now + Duration::from_secs(3600);
+		assert!(limiter.check_at(KEY.clone(), now).is_ok());
+		assert!(limiter.check_at(KEY.clone(), later).is_ok());
+		assert!(limiter.check_at(KEY.clone(), later).is_ok());
+		assert!(limiter.check_at(KEY.clone(), later).is_err());
+	}
+
+	#[test]
+	fn keeps_a_bucket_per_key() {
+		let limiter = limiter(1, 1);
+		let now = Instant::now();
+		assert!(limiter.check_at(Key::Session(1), now).is_ok());
+		assert!(limiter.check_at(Key::Session(1), now).is_err());
+		assert!(limiter.check_at(Key::Session(2), now).is_ok());
+		assert!(limiter
+			.check_at(Key::Ip([127, 0, 0, 1].into()), now)
+			.is_ok());
+	}
+
+	#[test]
+	fn gives_addresses_their_own_limit() {
+		let limiter = RateLimiter::new(
+			Limit {
+				burst: 1,
+				per_minute: 1,
+			},
+			Limit {
+				burst: 2,
+				per_minute: 1,
+			},
+		);
+		let now = Instant::now();
+		let ip = Key::Ip([127, 0, 0, 1].into());
+		assert!(limiter.check_at(KEY.clone(), now).is_ok());
+		assert!(limiter.check_at(KEY.clone(), now).is_err());
+		assert!(limiter.check_at(ip.clone(), now).is_ok());
+		assert!(limiter.check_at(ip.clone(), now).is_ok());
+		assert!(limiter.check_at(ip, now).is_err());
+	}
+
+	#[test]
+	fn never_refills_without_a_rate() {
+		let limiter = limiter(1, 0);
+		let now = Instant::now();
+		assert!(limiter.check_at(KEY.clone(), now).is_ok());
+		assert_eq!(
+			limiter.check_at(KEY.clone(), now + Duration::from_secs(3600)),
+			Err(Duration::from_secs(u64::MAX))
+		);
+	}
+}
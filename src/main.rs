@@ -1,7 +1,6 @@
 use std::io;
-use std::sync::Mutex;
 
-use crate::pagination::Paginate;
+use crate::pagination::{Paginate, Paginated};
 use actix_session::CookieSession;
 use actix_web::web::Data;
 use actix_web::{web, App, HttpResponse, HttpServer, Responder};
@@ -9,6 +8,7 @@ use diesel::prelude::*;
 use serde::Deserialize;
 
 use crate::chat::Broadcaster;
+use actix::Addr;
 use actix_web::middleware::Logger;
 use diesel::r2d2::ConnectionManager;
 use diesel::PgConnection;
@@ -19,17 +19,78 @@ use std::time::Duration;
 #[macro_use]
 extern crate diesel; //Needed for ORM macros
 
+mod bans;
 mod chat;
+mod cluster;
+mod color;
+mod error;
 mod get_paste;
+mod history;
+mod limiter;
+mod mentions;
 mod models;
+mod nicks;
 mod pagination;
+mod pastes;
+mod presences;
 mod schema;
+mod secret;
+mod ws;
+
+pub type Pool = r2d2::Pool<ConnectionManager<PgConnection>>;
 
 #[derive(Deserialize)]
-struct Config {
+pub struct Config {
 	ip: String,
 	port: u16,
 	database_url: String,
+	#[serde(default)]
+	history_retention: history::Retention,
+	/// Secret for the `/mod` command. Nobody can become a moderator if it's not set.
+	moderator_secret: Option<String>,
+	#[serde(default)]
+	rate_limits: limiter::RateLimits,
+	/// Relay chat events to the other instances using the same database
+	#[serde(default)]
+	cluster: bool,
+	/// Key signing the session cookies, at least 32 bytes long. Has to be the same
+	/// on every instance of a cluster. A random one is used if it's not set.
+	cookie_key: Option<String>,
+	/// Length of the random identifiers of pastes in their URLs
+	#[serde(default = "default_paste_slug_length")]
+	paste_slug_length: usize,
+	/// Take client addresses from the last entry of `X-Forwarded-For`, which is
+	/// the one added by the reverse proxy in front of the server.
+	#[serde(default)]
+	trust_proxy_headers: bool,
+}
+
+const MIN_COOKIE_KEY_LEN: usize = 32;
+/// Shorter slugs would be easy to guess, or run out quickly
+const MIN_PASTE_SLUG_LEN: usize = 8;
+
+fn default_paste_slug_length() -> usize {
+	10
+}
+
+/// Parses durations like `30s`, `10m`, `2h` or `1d`. Plain numbers are minutes.
+pub fn parse_duration(input: &str) -> Option<Duration> {
+	let (amount, unit) = match input.find(|c: char| !c.is_ascii_digit()) {
+		Some(idx) => input.split_at(idx),
+		None => (input, "m"),
+	};
+	let secs = match unit {
+		"s" => 1,
+		"m" => 60,
+		"h" => 60 * 60,
+		"d" => 24 * 60 * 60,
+		_ => return None,
+	};
+	amount
+		.parse::<u64>()
+		.ok()
+		.and_then(|amount| amount.checked_mul(secs))
+		.map(Duration::from_secs)
 }
 
 fn _try_ffsend_upload() -> io::Result<()> {
@@ -90,37 +151,95 @@ async fn main() -> io::Result<()> {
 		};
 		toml::from_slice::<Config>(data.as_slice())?
 	};
+	if config.paste_slug_length < MIN_PASTE_SLUG_LEN {
+		return Err(io::Error::new(
+			io::ErrorKind::InvalidInput,
+			format!(
+				"paste_slug_length has to be at least {}",
+				MIN_PASTE_SLUG_LEN
+			),
+		));
+	}
 
-	let conn_manager = ConnectionManager::<PgConnection>::new(config.database_url);
+	let conn_manager = ConnectionManager::<PgConnection>::new(config.database_url.clone());
 	let pool = r2d2::Pool::builder()
 		.max_size(4)
 		.build(conn_manager)
 		.expect("Failed to create Pool");
 
-	let broadcaster = chat::Broadcaster::new();
+	let highlighter = Data::new(get_paste::Highlighter::new());
+	let broadcaster = if config.cluster {
+		cluster::start(
+			config.database_url.clone(),
+			pool.clone(),
+			highlighter.clone(),
+		)
+	} else {
+		chat::Broadcaster::new(None)
+	};
+	history::start_cleaner(pool.clone(), config.history_retention);
+	pastes::start_reaper(pool.clone());
 
 	let bind_addr = format!("{}:{}", config.ip, config.port);
+	let limiters = Data::new(limiter::Limiters::new(config.rate_limits));
+	let config = Data::new(config);
 
-	let mut gen = rand::thread_rng();
-	let cookie_key = (0..64)
-		.into_iter()
-		.map(|_| gen.gen::<u8>())
-		.collect::<Vec<u8>>();
+	let cookie_key = match &config.cookie_key {
+		Some(key) if key.len() >= MIN_COOKIE_KEY_LEN => key.as_bytes().to_vec(),
+		Some(_) => {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidInput,
+				format!(
+					"cookie_key has to be at least {} bytes long",
+					MIN_COOKIE_KEY_LEN
+				),
+			))
+		}
+		// Sessions would only be valid on the instance that started them
+		None if config.cluster => {
+			return Err(io::Error::new(
+				io::ErrorKind::InvalidInput,
+				"cookie_key has to be set, to the same value on every instance, when cluster is enabled",
+			))
+		}
+		None => {
+			let mut gen = rand::thread_rng();
+			(0..64)
+				.into_iter()
+				.map(|_| gen.gen::<u8>())
+				.collect::<Vec<u8>>()
+		}
+	};
 
 	let server = HttpServer::new(move || {
 		use handlers::*;
 
 		App::new()
-			.wrap(Logger::default())
+			// Logs the path alone, since query strings carry nicks
+			.wrap(Logger::new(
+				"%a \"%U\" %s %b \"%{Referer}i\" \"%{User-Agent}i\" %T",
+			))
 			.data(pool.clone())
 			.wrap(CookieSession::signed(&cookie_key).secure(false))
-			.app_data(broadcaster.clone())
+			.data(broadcaster.clone())
+			.app_data(config.clone())
+			.app_data(limiters.clone())
+			.app_data(highlighter.clone())
+			.route("/claim_nick", web::post().to(claim_nick))
 			.route("/events", web::get().to(new_client))
+			.route("/ws", web::get().to(ws_connect))
 			.route("/send_msg", web::post().to(send_msg))
+			.route("/users", web::get().to(get_users))
+			.route("/history", web::get().to(get_history))
+			.route("/messages/{id}/thread", web::get().to(get_thread))
+			.route("/mentions/read", web::post().to(read_mentions))
+			.route("/typing", web::post().to(typing))
 			.route("/send_paste", web::post().to(send_paste))
 			.route("/get_pastes", web::get().to(get_pastes))
-			.route("/raw/{id}", web::get().to(get_paste_raw))
-			.route("/paste/{id}", web::get().to(get_paste))
+			.route("/raw/{slug}", web::get().to(get_paste_raw))
+			.route("/raw/{slug}", web::post().to(get_paste_raw))
+			.route("/paste/{slug}", web::get().to(get_paste))
+			.route("/paste/{slug}", web::post().to(get_paste))
 			.route("/send_cmd", web::post().to(chat_command))
 			.service(actix_files::Files::new("/", "frontend/dist").index_file("index.html"))
 	})
@@ -133,96 +252,632 @@ async fn main() -> io::Result<()> {
 }
 
 mod handlers {
-	type Pool = r2d2::Pool<ConnectionManager<PgConnection>>;
-
+	use crate::error::{ChatError, PageError};
+	use crate::get_paste::{Arg, Highlighter};
+	use crate::limiter::{Key, Limiters, RateLimiter};
 	use crate::*;
 	use actix_session::Session;
+	use actix_web::http::StatusCode;
 	use actix_web::web;
-	use diesel::r2d2::ConnectionManager;
-	use diesel::PgConnection;
+	use actix_web::HttpRequest;
+	use diesel::r2d2::PooledConnection;
+	use std::net::{IpAddr, SocketAddr};
+	use std::time::Duration;
+	use tokio::sync::mpsc;
 
 	#[derive(Deserialize)]
 	pub struct NewClientQueryParams {
 		nick: String,
 		color: Option<String>,
+		room: Option<String>,
 	}
 
-	pub async fn new_client(
-		params: web::Query<NewClientQueryParams>,
-		broadcaster: Data<Mutex<Broadcaster>>,
+	#[derive(Deserialize)]
+	pub struct ClaimNick {
+		nick: String,
+		secret: String,
+	}
+
+	/// Lets the session use the reserved nick `nick` from now on. The secret is
+	/// sent here rather than along with `/events`, which can only take it in the
+	/// query string.
+	pub async fn claim_nick(
+		req: HttpRequest,
+		claim: web::Json<ClaimNick>,
 		session: Session,
+		broadcaster: Data<Addr<Broadcaster>>,
+		pool: Data<Pool>,
+		limiters: Data<Limiters>,
 	) -> Result<impl Responder, actix_web::Error> {
-		let mut broadcaster = broadcaster.lock().unwrap();
-		session.set("nick", &params.nick).unwrap();
+		// Each guess costs a key derivation
+		let id = session.get::<u64>("id").unwrap_or_default();
+		check_limit(&limiters.unlocks, id, client_ip(&req), &broadcaster)?;
 
-		let (rx, new_user) = broadcaster.new_user(&params.nick);
+		let db_conn = db_conn(&pool)?;
+		nicks::check_reservation(&db_conn, &claim.nick, Some(&claim.secret))?;
+		session
+			.set("claimed_nick", claim.nick.to_lowercase())
+			.map_err(session_error)?;
 
-		session.set("id", new_user.id).unwrap();
+		Ok(HttpResponse::Ok().body(""))
+	}
 
-		new_user.color = params.color.clone();
+	pub async fn new_client(
+		req: HttpRequest,
+		params: web::Query<NewClientQueryParams>,
+		broadcaster: Data<Addr<Broadcaster>>,
+		session: Session,
+		pool: Data<Pool>,
+		config: Data<Config>,
+	) -> Result<impl Responder, actix_web::Error> {
+		// Sent by EventSource when it reconnects. Event ids are counted by each
+		// instance, so a client that may have been sent to another one can't resume.
+		let last_event_id = req
+			.headers()
+			.get("Last-Event-ID")
+			.filter(|_| !config.cluster)
+			.and_then(|id| id.to_str().ok())
+			.and_then(|id| id.parse::<u64>().ok());
 
-		new_user
-			.sender
-			.clone()
-			.try_send(chat::event_data(chat::Msg::connected(&broadcaster.history)))
-			.unwrap();
+		let ip = client_ip(&req);
+		let (_, rx) = join(&params, last_event_id, ip, &session, &broadcaster, &pool).await?;
 
 		Ok(HttpResponse::Ok()
 			.header("content-type", "text/event-stream")
 			.no_chunking()
-			.streaming(rx))
+			.streaming(chat::UserDataStream::new(rx)))
+	}
+
+	pub async fn ws_connect(
+		req: HttpRequest,
+		stream: web::Payload,
+		params: web::Query<NewClientQueryParams>,
+		broadcaster: Data<Addr<Broadcaster>>,
+		session: Session,
+		pool: Data<Pool>,
+	) -> Result<HttpResponse, actix_web::Error> {
+		let ip = client_ip(&req);
+		let (id, rx) = join(&params, None, ip, &session, &broadcaster, &pool).await?;
+
+		// Both are always registered by `main`
+		let config = req.app_data::<Data<Config>>().unwrap().clone();
+		let limiters = req.app_data::<Data<Limiters>>().unwrap().clone();
+		actix_web_actors::ws::start(
+			ws::WsSession::new(
+				id,
+				rx,
+				broadcaster.get_ref().clone(),
+				pool,
+				config,
+				limiters,
+			),
+			&req,
+			stream,
+		)
+	}
+
+	/// Opens a new stream in the requested room for the session's user and queues
+	/// the room history for it.
+	///
+	/// A client that still knows the id of the last event it received only gets the
+	/// events it missed, as long as the room has them in its backlog.
+	async fn join(
+		params: &NewClientQueryParams,
+		last_event_id: Option<u64>,
+		ip: Option<IpAddr>,
+		session: &Session,
+		broadcaster: &Addr<Broadcaster>,
+		pool: &Pool,
+	) -> Result<(u64, mpsc::Receiver<chat::Event>), ChatError> {
+		let room_name = params.room.as_deref().unwrap_or(chat::DEFAULT_ROOM);
+		if !chat::is_valid_room_name(room_name) {
+			return Err(ChatError::BadRequest(String::from("Invalid room name")));
+		}
+
+		let color = match params.color.as_deref() {
+			Some(color) if !color.is_empty() => Some(color::validate(color)?),
+			_ => None,
+		};
+
+		let db_conn = db_conn(pool)?;
+		let claimed_nick = session.get::<String>("claimed_nick").unwrap_or_default();
+		if claimed_nick != Some(params.nick.to_lowercase()) {
+			nicks::check_reservation(&db_conn, &params.nick, None)?;
+		}
+		bans::check(&db_conn, &params.nick, ip)?;
+
+		let resume_from = match last_event_id {
+			Some(last_event_id) => {
+				let can_resume = chat::CanResume {
+					room: String::from(room_name),
+					last_event_id,
+				};
+				if ask(broadcaster, can_resume).await? {
+					Some(last_event_id)
+				} else {
+					None
+				}
+			}
+			None => None,
+		};
+
+		let (history_loaded_at, history, unread_mentions) = match resume_from {
+			Some(_) => (None, Vec::new(), Vec::new()),
+			None => {
+				// Whatever is sent from now on is replayed once the stream is open
+				let loaded_at = ask(
+					broadcaster,
+					chat::LastEventId {
+						room: String::from(room_name),
+					},
+				)
+				.await?;
+				let loaded = history::load(&db_conn, room_name).and_then(|history| {
+					let unread = mentions::unread(&db_conn, &params.nick, room_name)?;
+					Ok((history, unread))
+				});
+				match loaded {
+					Ok((history, unread)) => (loaded_at, history, unread),
+					Err(e) => {
+						println!("Error loading chat history: {}", e);
+						return Err(ChatError::Unavailable);
+					}
+				}
+			}
+		};
+
+		let connect = chat::Connect {
+			id: session.get::<u64>("id").unwrap_or_default(),
+			room: String::from(room_name),
+			nick: params.nick.clone(),
+			color,
+			ip,
+			resume_from,
+			history_loaded_at,
+			history,
+			mentions: unread_mentions,
+		};
+		let (id, nick, rx) = ask(broadcaster, connect).await??;
+
+		session.set("nick", &nick).map_err(session_error)?;
+		session.set("id", id).map_err(session_error)?;
+
+		Ok((id, rx))
+	}
+
+	#[derive(Deserialize)]
+	pub struct NewMsg {
+		room: String,
+		msg: String,
+		/// Id of the message this one replies to
+		#[serde(default)]
+		reply_to: Option<i64>,
 	}
 
 	pub async fn send_msg(
-		msg: web::Json<String>,
-		broadcaster: Data<Mutex<Broadcaster>>,
+		msg: web::Json<NewMsg>,
+		broadcaster: Data<Addr<Broadcaster>>,
+		session: Session,
+		pool: Data<Pool>,
+		limiters: Data<Limiters>,
+	) -> Result<impl Responder, actix_web::Error> {
+		let id = match session.get::<u64>("id")? {
+			Some(id) => id,
+			None => return Err(ChatError::Unauthorized.into()),
+		};
+		post_msg(id, msg.into_inner(), &broadcaster, &pool, &limiters).await?;
+
+		Ok(HttpResponse::Ok().body(""))
+	}
+
+	/// Persists a message from the user `id` and broadcasts it to its room.
+	pub async fn post_msg(
+		id: u64,
+		msg: NewMsg,
+		broadcaster: &Addr<Broadcaster>,
+		pool: &Pool,
+		limiters: &Limiters,
+	) -> Result<(), ChatError> {
+		let NewMsg {
+			room,
+			msg,
+			reply_to,
+		} = msg;
+
+		let user = unmuted_user(broadcaster, id).await?;
+		if !user.is_in_room(&room) {
+			return Err(ChatError::NotFound);
+		}
+		check_limit(&limiters.messages, Some(id), user.ip, broadcaster)?;
+
+		let db_conn = db_conn(pool)?;
+		// Threads are flat, so replying to a reply continues the same thread
+		let reply_to = match reply_to {
+			Some(reply_to) => {
+				let parent = find_message(&db_conn, reply_to)?;
+				if parent.room != room {
+					return Err(ChatError::NotFound);
+				}
+				Some(parent.reply_to.unwrap_or(parent.id))
+			}
+			None => None,
+		};
+
+		let new_msg = models::NewUserMsg {
+			room,
+			nick: user.nick,
+			custom_nick_color: user.color,
+			msg,
+			time: chrono::Utc::now(),
+			author_id: id as i64,
+			reply_to,
+		};
+		let user_msg = match history::insert(&db_conn, new_msg) {
+			Ok(user_msg) => user_msg,
+			Err(e) => {
+				println!("Error inserting new message: {}", e);
+				return Err(ChatError::Unavailable);
+			}
+		};
+
+		let user_msg_id = user_msg.id;
+		let mentioned = ask(broadcaster, chat::SendMsg(user_msg)).await?;
+		if !mentioned.is_empty() {
+			if let Err(e) = mentions::save(&db_conn, user_msg_id, &mentioned) {
+				println!("Error saving mentions: {}", e);
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Takes a token from the buckets of both the session `id` and the address
+	/// `ip`, since a new session is only a reconnect away.
+	fn check_limit(
+		limiter: &RateLimiter,
+		id: Option<u64>,
+		ip: Option<IpAddr>,
+		broadcaster: &Addr<Broadcaster>,
+	) -> Result<(), ChatError> {
+		let keys = id.map(Key::Session).into_iter().chain(ip.map(Key::Ip));
+		for key in keys {
+			if let Err(retry_after) = limiter.check(key) {
+				return Err(rate_limited(id, retry_after, broadcaster));
+			}
+		}
+		Ok(())
+	}
+
+	/// Lets the user `id` know they're sending too much, and returns the error for
+	/// the request that got limited.
+	fn rate_limited(
+		id: Option<u64>,
+		retry_after: Duration,
+		broadcaster: &Addr<Broadcaster>,
+	) -> ChatError {
+		let retry_after = retry_after.as_secs().saturating_add(1);
+		if let Some(id) = id {
+			let rate_limited = chat::RateLimited { retry_after };
+			broadcaster.do_send(chat::SendTo {
+				id,
+				event: chat::event_data(chat::Msg::rate_limited_msg(&rate_limited)),
+			});
+		}
+		ChatError::RateLimited(retry_after)
+	}
+
+	/// Sends `msg` to the broadcaster and waits for its answer.
+	async fn ask<M>(broadcaster: &Addr<Broadcaster>, msg: M) -> Result<M::Result, ChatError>
+	where
+		M: actix::Message + Send + 'static,
+		M::Result: Send,
+		Broadcaster: actix::Handler<M>,
+	{
+		broadcaster.send(msg).await.map_err(|e| {
+			println!("Failed to reach the broadcaster: {}", e);
+			ChatError::Internal
+		})
+	}
+
+	/// Makes sure the user `id` is still connected.
+	async fn connected_user(
+		broadcaster: &Addr<Broadcaster>,
+		id: u64,
+	) -> Result<chat::UserInfo, ChatError> {
+		ask(broadcaster, chat::GetUser { id })
+			.await?
+			.ok_or(ChatError::Unauthorized)
+	}
+
+	/// Makes sure the user `id` is connected, and allowed to send anything to the
+	/// other users.
+	async fn unmuted_user(
+		broadcaster: &Addr<Broadcaster>,
+		id: u64,
+	) -> Result<chat::UserInfo, ChatError> {
+		let user = connected_user(broadcaster, id).await?;
+		check_muted(user.muted_for)?;
+		Ok(user)
+	}
+
+	fn check_muted(muted_for: Option<Duration>) -> Result<(), ChatError> {
+		match muted_for {
+			Some(muted_for) => Err(ChatError::Forbidden(format!(
+				"You are muted for {}s",
+				muted_for.as_secs().saturating_add(1)
+			))),
+			None => Ok(()),
+		}
+	}
+
+	/// Address of the client, as told by the reverse proxy if `trust_proxy_headers`
+	/// is set.
+	pub fn client_ip(req: &HttpRequest) -> Option<IpAddr> {
+		// Always registered by `main`
+		let config = req.app_data::<Data<Config>>().unwrap();
+		let peer_ip = req.peer_addr().map(|addr| addr.ip());
+		if !config.trust_proxy_headers {
+			return peer_ip;
+		}
+
+		// The proxy appends the address it got the request from, everything before
+		// it was sent by the client
+		let forwarded_for = req
+			.headers()
+			.get_all("X-Forwarded-For")
+			.filter_map(|value| value.to_str().ok())
+			.flat_map(|value| value.split(','))
+			.last();
+		match forwarded_for {
+			Some(addr) => parse_ip(addr.trim()),
+			None => peer_ip,
+		}
+	}
+
+	/// Parses either `1.2.3.4`, `1.2.3.4:5678`, `"[::1]:5678"` or `[::1]`.
+	fn parse_ip(addr: &str) -> Option<IpAddr> {
+		let addr = addr.trim_matches('"');
+		addr.parse::<SocketAddr>()
+			.map(|addr| addr.ip())
+			.or_else(|_| addr.trim_start_matches('[').trim_end_matches(']').parse())
+			.ok()
+	}
+
+	/// Makes sure the user `id` is a connected moderator.
+	async fn moderator(
+		broadcaster: &Addr<Broadcaster>,
+		id: u64,
+	) -> Result<chat::UserInfo, ChatError> {
+		let user = connected_user(broadcaster, id).await?;
+		if !user.is_moderator {
+			return Err(ChatError::Forbidden(String::from(
+				"Only moderators can do that",
+			)));
+		}
+		Ok(user)
+	}
+
+	fn session_error(e: actix_web::Error) -> ChatError {
+		println!("Failed to save session: {}", e);
+		ChatError::Internal
+	}
+
+	fn db_conn(
+		pool: &Pool,
+	) -> Result<PooledConnection<ConnectionManager<PgConnection>>, ChatError> {
+		pool.get().map_err(|e| {
+			println!("Failed to get connection to the database: {}", e);
+			ChatError::Unavailable
+		})
+	}
+
+	#[derive(Deserialize)]
+	pub struct TypingNotice {
+		room: String,
+	}
+
+	pub async fn typing(
+		notice: web::Json<TypingNotice>,
+		broadcaster: Data<Addr<Broadcaster>>,
 		session: Session,
 	) -> Result<impl Responder, actix_web::Error> {
 		let id = match session.get::<u64>("id")? {
 			Some(id) => id,
-			None => return Ok(HttpResponse::Unauthorized().body("")),
+			None => return Err(ChatError::Unauthorized.into()),
 		};
-		broadcaster.lock().unwrap().send(id, msg.0.clone());
+		notify_typing(id, notice.into_inner(), &broadcaster).await?;
 
 		Ok(HttpResponse::Ok().body(""))
 	}
 
+	pub async fn notify_typing(
+		id: u64,
+		notice: TypingNotice,
+		broadcaster: &Addr<Broadcaster>,
+	) -> Result<(), ChatError> {
+		unmuted_user(broadcaster, id).await?;
+		let typing = chat::IsTyping {
+			id,
+			room: notice.room,
+		};
+		if ask(broadcaster, typing).await? {
+			Ok(())
+		} else {
+			Err(ChatError::NotFound)
+		}
+	}
+
+	/// Marks all mentions of the session's nick as read.
+	pub async fn read_mentions(
+		broadcaster: Data<Addr<Broadcaster>>,
+		session: Session,
+		pool: Data<Pool>,
+	) -> Result<impl Responder, actix_web::Error> {
+		let id = match session.get::<u64>("id")? {
+			Some(id) => id,
+			None => return Err(ChatError::Unauthorized.into()),
+		};
+		let user = connected_user(&broadcaster, id).await?;
+
+		let db_conn = db_conn(&pool)?;
+		if let Err(e) = mentions::mark_read(&db_conn, &user.nick) {
+			println!("Error marking mentions as read: {}", e);
+			return Err(ChatError::Unavailable.into());
+		}
+		Ok(HttpResponse::Ok().body(""))
+	}
+
+	#[derive(Deserialize)]
+	pub struct GetHistoryQuery {
+		room: String,
+	}
+
+	/// Same history as the one sent on connect, for clients told to `Resync`.
+	pub async fn get_history(
+		query: web::Query<GetHistoryQuery>,
+		session: Session,
+		pool: Data<Pool>,
+	) -> Result<impl Responder, actix_web::Error> {
+		if session.get::<String>("nick")?.is_none() {
+			return Err(ChatError::Unauthorized.into());
+		}
+
+		let db_conn = db_conn(&pool)?;
+		match history::load(&db_conn, &query.room) {
+			Ok(history) => Ok(HttpResponse::Ok().json(history)),
+			Err(e) => {
+				println!("Error loading chat history: {}", e);
+				Err(ChatError::Unavailable.into())
+			}
+		}
+	}
+
+	pub async fn get_thread(
+		path: web::Path<(i64,)>,
+		session: Session,
+		pool: Data<Pool>,
+	) -> Result<impl Responder, actix_web::Error> {
+		if session.get::<String>("nick")?.is_none() {
+			return Err(ChatError::Unauthorized.into());
+		}
+
+		let db_conn = db_conn(&pool)?;
+		match history::load_thread(&db_conn, path.0) {
+			Ok(Some(thread)) => Ok(HttpResponse::Ok().json(thread)),
+			Ok(None) => Err(ChatError::NotFound.into()),
+			Err(e) => {
+				println!("Error loading thread: {}", e);
+				Err(ChatError::Unavailable.into())
+			}
+		}
+	}
+
+	#[derive(Deserialize)]
+	pub struct GetUsersQuery {
+		room: Option<String>,
+	}
+
+	pub async fn get_users(
+		query: web::Query<GetUsersQuery>,
+		broadcaster: Data<Addr<Broadcaster>>,
+		session: Session,
+	) -> Result<impl Responder, actix_web::Error> {
+		if session.get::<String>("nick")?.is_none() {
+			return Err(ChatError::Unauthorized.into());
+		}
+
+		let online = chat::Online {
+			room: query.into_inner().room,
+		};
+		let users = ask(&broadcaster, online).await?;
+
+		Ok(HttpResponse::Ok().json(users))
+	}
+
 	#[derive(Deserialize)]
 	pub struct NewPaste {
 		filename: String,
 		content: String,
+		/// How long the paste can be viewed for, like `1h`
+		expires_in: Option<String>,
+		/// How many times the paste can be viewed before it's gone
+		max_views: Option<i32>,
+		/// Needed to view the paste, if set
+		password: Option<String>,
 	}
 
 	pub async fn send_paste(
+		req: HttpRequest,
 		new_paste: web::Json<NewPaste>,
-		broadcaster: Data<Mutex<Broadcaster>>,
+		broadcaster: Data<Addr<Broadcaster>>,
 		session: Session,
 		pool: Data<Pool>,
+		limiters: Data<Limiters>,
+		config: Data<Config>,
 	) -> Result<impl Responder, actix_web::Error> {
-		if let None = session.get::<String>("nick")? {
-			return Ok(HttpResponse::Unauthorized().body(""));
+		let nick = match session.get::<String>("nick")? {
+			Some(nick) => nick,
+			None => return Err(ChatError::Unauthorized.into()),
+		};
+
+		let id = session.get::<u64>("id")?;
+		let ip = client_ip(&req);
+		if id.is_none() && ip.is_none() {
+			return Err(ChatError::Unauthorized.into());
+		}
+		// Kicks and bans leave the nick in the session cookie
+		let db_conn = db_conn(&pool)?;
+		bans::check(&db_conn, &nick, ip)?;
+		check_muted(ask(&broadcaster, chat::MutedFor { nick, ip }).await?)?;
+		let NewPaste {
+			filename,
+			content,
+			expires_in,
+			max_views,
+			password,
+		} = new_paste.into_inner();
+		let expires_at = match expires_in {
+			Some(expires_in) => Some(
+				parse_duration(&expires_in)
+					.and_then(|expires_in| chrono::Duration::from_std(expires_in).ok())
+					.and_then(|expires_in| now().checked_add_signed(expires_in))
+					.ok_or_else(|| {
+						ChatError::BadRequest(String::from(
+							"Invalid expiry, use e.g. 10m, 1h or 7d",
+						))
+					})?,
+			),
+			None => None,
+		};
+		if max_views.is_some_and(|max_views| max_views < 1) {
+			return Err(ChatError::BadRequest(String::from(
+				"A paste has to allow at least one view",
+			))
+			.into());
 		}
 
+		check_limit(&limiters.pastes, id, ip, &broadcaster)?;
+
 		let new_paste = models::Paste {
 			id: 0,
-			filename: Some(new_paste.0.filename),
-			content: Some(new_paste.0.content),
+			slug: String::new(),
+			filename: Some(filename),
+			content: Some(content),
 			creation_date: now(),
+			expires_at,
+			max_views,
+			views: 0,
+			password_hash: password
+				.filter(|password| !password.is_empty())
+				.map(|password| secret::hash(&password)),
 		};
 
-		use crate::schema::pastes::dsl::pastes;
-		let paste = match diesel::insert_into(pastes)
-			.values(new_paste)
-			.get_result::<models::Paste>(&pool.get().unwrap())
-		{
-			Ok(paste) => paste,
-			Err(e) => {
-				println!("Error inserting new paste: {}", e);
-				return Ok(HttpResponse::InternalServerError().body(""));
-			}
-		};
+		let paste = pastes::insert(&db_conn, new_paste, config.paste_slug_length)?;
+		// Always registered by `main`
+		let highlighter = req.app_data::<Data<Highlighter>>().unwrap().clone();
+		let mut listed = list_pastes(highlighter, vec![paste]).await?;
 
-		broadcaster.lock().unwrap().send_paste(paste);
+		broadcaster.do_send(chat::SendPaste(listed.remove(0)));
 
 		Ok(HttpResponse::Ok().body(""))
 	}
@@ -233,136 +888,479 @@ mod handlers {
 		per_page: Option<i64>,
 	}
 
+	/// Most pastes listed at once, since each one gets highlighted
+	const MAX_PASTES_PER_PAGE: i64 = 50;
+
 	pub async fn get_pastes(
 		query: web::Query<GetPastesQuery>,
 		session: Session,
 		pool: Data<Pool>,
-	) -> impl Responder {
-		if let None = session.get::<String>("nick").unwrap() {
-			return HttpResponse::Unauthorized().body("");
+		highlighter: Data<Highlighter>,
+	) -> Result<impl Responder, actix_web::Error> {
+		if session.get::<String>("nick")?.is_none() {
+			return Err(ChatError::Unauthorized.into());
 		}
 
-		let db_conn = match pool.get() {
-			Ok(conn) => conn,
-			Err(e) => {
-				println!("Failed to get connection to the database: {}", e);
-				return HttpResponse::InternalServerError().body("");
-			}
-		};
+		let db_conn = db_conn(&pool)?;
 		let pastes = {
 			use crate::schema::pastes::dsl::*;
 
+			let now = now();
 			pastes
+				.filter(expires_at.is_null().or(expires_at.gt(now)))
+				.filter(max_views.is_null().or(views.nullable().lt(max_views)))
 				.order(id.desc())
-				.paginate(query.page.unwrap_or(1), query.per_page.unwrap_or(10))
+				.paginate(
+					query.page.unwrap_or(1),
+					query.per_page.unwrap_or(10).min(MAX_PASTES_PER_PAGE),
+				)
 				.load_and_count_pages::<models::Paste>(&db_conn)
 		};
-		let pastes = match pastes {
-			Ok(pastes) => pastes,
-			Err(e) => {
-				println!("Error getting pastes: {}", e);
-				return HttpResponse::InternalServerError().body("");
-			}
+		let pastes = pastes.map_err(|e| {
+			println!("Error getting pastes: {}", e);
+			ChatError::Unavailable
+		})?;
+		let listed = Paginated {
+			page: pastes.page,
+			total_pages: pastes.total_pages,
+			results: list_pastes(highlighter, pastes.results).await?,
 		};
-		HttpResponse::Ok()
-			.content_type("application/json")
-			.body(serde_json::to_string(&pastes).unwrap())
+
+		Ok(HttpResponse::Ok().json(listed))
 	}
 
-	pub async fn get_paste_raw(path: web::Path<i64>, pool: Data<Pool>) -> impl Responder {
-		let requested_id = *path;
+	/// Hides what `pastes` don't show in lists, and highlights the rest.
+	async fn list_pastes(
+		highlighter: Data<Highlighter>,
+		pastes: Vec<models::Paste>,
+	) -> Result<Vec<models::ListedPaste>, ChatError> {
+		// Highlighting a long paste takes a while, so it's kept off the workers
+		web::block(move || {
+			Ok::<_, ()>(
+				pastes
+					.into_iter()
+					.map(|paste| highlighter.listed(paste))
+					.collect(),
+			)
+		})
+		.await
+		.map_err(|e| {
+			println!("Failed to highlight pastes: {}", e);
+			ChatError::Internal
+		})
+	}
 
-		let db_conn = match pool.get() {
-			Ok(conn) => conn,
-			Err(e) => {
-				println!("Failed to get connection to the database: {}", e);
-				return HttpResponse::InternalServerError().body("");
-			}
-		};
+	/// Header the password of a locked paste can be sent in, instead of a form
+	const PASSWORD_HEADER: &str = "X-Paste-Password";
 
-		let paste = {
-			use crate::schema::pastes::dsl::*;
+	#[derive(Deserialize)]
+	pub struct PastePassword {
+		password: String,
+	}
 
-			pastes
-				.filter(id.eq(requested_id))
-				.first::<models::Paste>(&db_conn)
-				.expect("Database error")
+	/// Password sent along a paste request, if any. Attempts are rate limited per IP.
+	fn paste_password(
+		req: &HttpRequest,
+		form: Option<web::Form<PastePassword>>,
+		limiters: &Limiters,
+	) -> Result<Option<String>, ChatError> {
+		let password = match form {
+			Some(form) => Some(form.into_inner().password),
+			None => req
+				.headers()
+				.get(PASSWORD_HEADER)
+				.and_then(|value| value.to_str().ok())
+				.map(String::from),
 		};
+		if let (Some(_), Some(ip)) = (&password, client_ip(req)) {
+			limiters.unlocks.check(Key::Ip(ip)).map_err(|retry_after| {
+				ChatError::RateLimited(retry_after.as_secs().saturating_add(1))
+			})?;
+		}
+		Ok(password)
+	}
 
-		HttpResponse::Ok()
+	pub async fn get_paste_raw(
+		req: HttpRequest,
+		path: web::Path<String>,
+		form: Option<web::Form<PastePassword>>,
+		pool: Data<Pool>,
+		limiters: Data<Limiters>,
+	) -> Result<HttpResponse, ChatError> {
+		let password = paste_password(&req, form, &limiters)?;
+		let db_conn = db_conn(&pool)?;
+		let paste = pastes::view(&db_conn, &path, password.as_deref())?;
+
+		Ok(HttpResponse::Ok()
 			.content_type("text/plain charset=UTF-8")
-			.body(paste.content.unwrap_or_default())
+			.body(paste.content.unwrap_or_default()))
 	}
 
-	pub async fn get_paste(path: web::Path<i64>, pool: Data<Pool>) -> impl Responder {
-		let requested_id = *path;
-
-		let db_conn = match pool.get() {
-			Ok(conn) => conn,
-			Err(e) => {
-				println!("Failed to get connection to the database: {}", e);
-				return HttpResponse::InternalServerError().body("");
+	pub async fn get_paste(
+		req: HttpRequest,
+		path: web::Path<String>,
+		form: Option<web::Form<PastePassword>>,
+		pool: Data<Pool>,
+		limiters: Data<Limiters>,
+		highlighter: Data<Highlighter>,
+	) -> Result<HttpResponse, PageError> {
+		let password = paste_password(&req, form, &limiters)?;
+		let db_conn = db_conn(&pool)?;
+		let paste = match pastes::view(&db_conn, &path, password.as_deref()) {
+			Ok(paste) => paste,
+			Err(ChatError::Unauthorized) => {
+				return Ok(locked_page(
+					StatusCode::UNAUTHORIZED,
+					"This paste is locked",
+				)?)
 			}
+			Err(ChatError::Forbidden(msg)) => return Ok(locked_page(StatusCode::FORBIDDEN, &msg)?),
+			Err(e) => return Err(e.into()),
 		};
 
-		let paste: models::Paste = {
-			use crate::schema::pastes::dsl::*;
+		let title = paste.filename.clone().unwrap_or_else(|| paste.slug.clone());
+		// Highlighting a long paste takes a while, so it's kept off the workers
+		let html = web::block(move || Ok::<_, ()>(highlighter.highlight(&paste)))
+			.await
+			.map_err(|e| {
+				println!("Failed to highlight paste: {}", e);
+				ChatError::Internal
+			})?;
 
-			pastes
-				.filter(id.eq(requested_id))
-				.first::<models::Paste>(&db_conn)
-				.expect("Database error")
-		};
+		let page = get_paste::PasteRenderer::new(&[Arg::Text(title.into_bytes()), Arg::Html(html)])
+			.map_err(template_error)?;
+		Ok(HttpResponse::Ok()
+			.content_type("text/html charset=UTF-8")
+			.streaming(page))
+	}
 
-		HttpResponse::Ok()
+	fn locked_page(status: StatusCode, message: &str) -> Result<HttpResponse, ChatError> {
+		let page = get_paste::PasteRenderer::locked(&[Arg::Text(message.as_bytes().to_vec())])
+			.map_err(template_error)?;
+		Ok(HttpResponse::build(status)
 			.content_type("text/html charset=UTF-8")
-			.streaming(
-				get_paste::PasteRenderer::new(&[
-					paste
-						.filename
-						.clone()
-						.unwrap_or(paste.id.to_string())
-						.into_bytes(),
-					paste.content.unwrap_or_default().into_bytes(),
-				])
-				.expect("io failed :("),
-			)
+			.streaming(page))
+	}
+
+	fn template_error(e: io::Error) -> ChatError {
+		println!("Failed to load page template: {}", e);
+		ChatError::Internal
 	}
 
 	#[derive(Deserialize)]
 	pub enum ChatCommand {
 		Color(String),
+		/// New nick in the form of `<nick> [secret]`
 		Nick(String),
+		/// Private message in the form of `<nick> <text>`
+		Msg(String),
+		/// Reserves the current nick with the given secret
+		Register(String),
+		/// Edits a message, in the form of `<message id> <new text>`
+		Edit(String),
+		/// Deletes the message with the given id
+		Delete(String),
+		/// Grants moderator rights to the user knowing the moderator secret
+		Mod(String),
+		/// Toggles a reaction to a message, in the form of `<message id> <emoji>`
+		React(String),
+		/// Disconnects the user with the given nick. Moderators only.
+		Kick(String),
+		/// Keeps a user from sending messages, in the form of `<nick> <duration>`,
+		/// like `10m`. Moderators only.
+		Mute(String),
+		/// Kicks a user and bans their nick and IP. Moderators only.
+		Ban(String),
+		/// Lifts the bans of a nick or an IP. Moderators only.
+		Unban(String),
 	}
 
 	pub async fn chat_command(
 		cmd: web::Json<ChatCommand>,
 		session: Session,
-		broadcaster: Data<Mutex<Broadcaster>>,
+		broadcaster: Data<Addr<Broadcaster>>,
+		pool: Data<Pool>,
+		config: Data<Config>,
+		limiters: Data<Limiters>,
 	) -> Result<impl Responder, actix_web::Error> {
 		let id = match session.get::<u64>("id")? {
 			Some(id) => id,
-			None => return Ok(HttpResponse::Unauthorized().body("")),
+			None => return Err(ChatError::Unauthorized.into()),
+		};
+		let new_nick = match &cmd.0 {
+			ChatCommand::Nick(args) => {
+				parse_nick_args(args).map(|(nick, secret)| (String::from(nick), secret.is_some()))
+			}
+			_ => None,
 		};
-		let mut broadcaster = broadcaster.lock().unwrap();
-		let user = &mut broadcaster.users.iter_mut().find(|u| u.id == id).unwrap();
 
-		match cmd.0 {
+		run_command(
+			id,
+			cmd.into_inner(),
+			&broadcaster,
+			&pool,
+			&config,
+			&limiters,
+		)
+		.await?;
+
+		if let Some((nick, claimed)) = new_nick {
+			// Lets the client reconnect with it without sending the secret again
+			if claimed {
+				session.set("claimed_nick", nick.to_lowercase())?;
+			}
+			session.set("nick", &nick)?;
+		}
+		Ok(HttpResponse::Ok().body(""))
+	}
+
+	pub async fn run_command(
+		id: u64,
+		cmd: ChatCommand,
+		broadcaster: &Addr<Broadcaster>,
+		pool: &Pool,
+		config: &Config,
+		limiters: &Limiters,
+	) -> Result<(), ChatError> {
+		match cmd {
 			ChatCommand::Color(color) => {
-				user.sender
-					.try_send(chat::event_data(chat::Msg::color_change_msg(&color)))
-					.unwrap();
-				user.color = Some(color);
+				// An empty color resets the nick to the default color
+				let color = match color.trim() {
+					"" => None,
+					color => Some(color::validate(color)?),
+				};
+				connected_user(broadcaster, id).await?;
+				broadcaster.do_send(chat::SetColor { id, color });
+			}
+			ChatCommand::Nick(args) => {
+				let (nick, secret) = parse_nick_args(&args).ok_or_else(|| {
+					ChatError::BadRequest(String::from("Usage: /nick <nick> [secret]"))
+				})?;
+				let user = unmuted_user(broadcaster, id).await?;
+				// Renames are announced to every room the user is in
+				check_limit(&limiters.messages, Some(id), user.ip, broadcaster)?;
+				if secret.is_some() {
+					check_limit(&limiters.unlocks, Some(id), user.ip, broadcaster)?;
+				}
+				let db_conn = db_conn(pool)?;
+				nicks::check_reservation(&db_conn, nick, secret)?;
+				bans::check(&db_conn, nick, user.ip)?;
+
+				let rename = chat::Rename {
+					id,
+					nick: String::from(nick),
+				};
+				ask(broadcaster, rename).await??;
+			}
+			ChatCommand::Msg(args) => {
+				let (to, msg) = match args.trim_start().split_once(' ') {
+					Some((to, msg)) if !msg.trim().is_empty() => (to, msg.trim()),
+					_ => {
+						return Err(ChatError::BadRequest(String::from(
+							"Usage: /msg <nick> <text>",
+						)));
+					}
+				};
+				let user = unmuted_user(broadcaster, id).await?;
+				check_limit(&limiters.messages, Some(id), user.ip, broadcaster)?;
+				let private_msg = chat::SendPrivate {
+					id,
+					to: String::from(to),
+					msg: String::from(msg),
+				};
+				if !ask(broadcaster, private_msg).await? {
+					return Err(ChatError::NotFound);
+				}
+			}
+			ChatCommand::Register(secret) => {
+				let secret = secret.trim();
+				if secret.is_empty() {
+					return Err(ChatError::BadRequest(String::from(
+						"Usage: /register <secret>",
+					)));
+				}
+				let user = connected_user(broadcaster, id).await?;
+				let db_conn = db_conn(pool)?;
+				nicks::reserve(&db_conn, &user.nick, secret)?;
+			}
+			ChatCommand::Edit(args) => {
+				let (msg_id, msg) = match args.trim_start().split_once(' ') {
+					Some((msg_id, msg)) if !msg.trim().is_empty() => {
+						(msg_id.parse::<i64>().ok(), msg.trim())
+					}
+					_ => (None, ""),
+				};
+				let msg_id = msg_id.ok_or_else(|| {
+					ChatError::BadRequest(String::from("Usage: /edit <message id> <text>"))
+				})?;
+
+				let db_conn = db_conn(pool)?;
+				let user = check_message_access(&db_conn, broadcaster, id, msg_id).await?;
+				check_muted(user.muted_for)?;
+				check_limit(&limiters.messages, Some(id), user.ip, broadcaster)?;
+				let edited = history::edit(&db_conn, msg_id, msg).map_err(|e| {
+					println!("Error editing message: {}", e);
+					ChatError::Unavailable
+				})?;
+				broadcaster.do_send(chat::SendEdit(edited));
+			}
+			ChatCommand::Delete(args) => {
+				let msg_id = args.trim().parse::<i64>().map_err(|_| {
+					ChatError::BadRequest(String::from("Usage: /delete <message id>"))
+				})?;
+
+				let db_conn = db_conn(pool)?;
+				let user = check_message_access(&db_conn, broadcaster, id, msg_id).await?;
+				check_muted(user.muted_for)?;
+				check_limit(&limiters.messages, Some(id), user.ip, broadcaster)?;
+				let deleted = history::delete(&db_conn, msg_id).map_err(|e| {
+					println!("Error deleting message: {}", e);
+					ChatError::Unavailable
+				})?;
+				broadcaster.do_send(chat::SendDelete(deleted));
+			}
+			ChatCommand::React(args) => {
+				let (msg_id, emoji) = match args.trim().split_once(' ') {
+					Some((msg_id, emoji)) => (msg_id.parse::<i64>().ok(), emoji.trim()),
+					None => (None, ""),
+				};
+				let msg_id = msg_id.ok_or_else(|| {
+					ChatError::BadRequest(String::from("Usage: /react <message id> <emoji>"))
+				})?;
+				if !chat::is_valid_reaction(emoji) {
+					return Err(ChatError::BadRequest(String::from("Invalid reaction")));
+				}
+
+				let db_conn = db_conn(pool)?;
+				let user_msg = find_message(&db_conn, msg_id)?;
+				let user = unmuted_user(broadcaster, id).await?;
+				if !user.is_in_room(&user_msg.room) {
+					return Err(ChatError::NotFound);
+				}
+				check_limit(&limiters.messages, Some(id), user.ip, broadcaster)?;
+
+				let reaction = models::Reaction {
+					message_id: msg_id,
+					user_id: id as i64,
+					emoji: String::from(emoji),
+				};
+				let reactions = history::toggle_reaction(&db_conn, reaction).map_err(|e| {
+					println!("Error saving reaction: {}", e);
+					ChatError::Unavailable
+				})?;
+				broadcaster.do_send(chat::SendReactions {
+					room: user_msg.room,
+					reactions: chat::ReactionsMsg {
+						message_id: msg_id,
+						reactions,
+					},
+				});
 			}
-			ChatCommand::Nick(nick) => {
-				session.set("nick", &nick).expect("Failed to change nick");
-				user.sender
-					.try_send(chat::event_data(chat::Msg::nick_change_msg(&nick)))
-					.unwrap();
-				user.nick = nick;
+			ChatCommand::Kick(nick) => {
+				moderator(broadcaster, id).await?;
+				let target = find_user(broadcaster, nick.trim()).await?;
+				broadcaster.do_send(chat::Kick { nick: target.nick });
+			}
+			ChatCommand::Mute(args) => {
+				let (nick, duration) = match args.trim().split_once(' ') {
+					Some((nick, duration)) => (nick, parse_duration(duration.trim())),
+					None => (args.trim(), None),
+				};
+				let muted_until = duration
+					.and_then(|duration| std::time::Instant::now().checked_add(duration))
+					.ok_or_else(|| {
+						ChatError::BadRequest(String::from("Usage: /mute <nick> <duration>"))
+					})?;
+
+				moderator(broadcaster, id).await?;
+				let target = find_user(broadcaster, nick).await?;
+				broadcaster.do_send(chat::Mute {
+					nick: target.nick,
+					ip: target.ip,
+					until: muted_until,
+				});
+			}
+			ChatCommand::Ban(nick) => {
+				let nick = nick.trim();
+				moderator(broadcaster, id).await?;
+				let find = chat::FindUser {
+					nick: String::from(nick),
+				};
+				let target = ask(broadcaster, find).await?;
+
+				let db_conn = db_conn(pool)?;
+				bans::ban(&db_conn, nick, target.as_ref().and_then(|target| target.ip))?;
+				if let Some(target) = target {
+					broadcaster.do_send(chat::Kick { nick: target.nick });
+				}
+			}
+			ChatCommand::Unban(target) => {
+				moderator(broadcaster, id).await?;
+				let db_conn = db_conn(pool)?;
+				bans::unban(&db_conn, target.trim())?;
+			}
+			ChatCommand::Mod(secret) => {
+				let user = connected_user(broadcaster, id).await?;
+				check_limit(&limiters.unlocks, Some(id), user.ip, broadcaster)?;
+				let valid = config
+					.moderator_secret
+					.as_deref()
+					.is_some_and(|moderator_secret| {
+						crate::secret::eq(moderator_secret, secret.trim())
+					});
+				if !valid {
+					return Err(ChatError::Forbidden(String::from("Invalid secret")));
+				}
+				broadcaster.do_send(chat::SetModerator { id });
 			}
 		}
-		Ok(HttpResponse::Ok().body(""))
+		Ok(())
+	}
+
+	fn find_message(conn: &PgConnection, msg_id: i64) -> Result<models::UserMsg, ChatError> {
+		match history::find(conn, msg_id) {
+			Ok(Some(user_msg)) if !user_msg.deleted => Ok(user_msg),
+			Ok(_) => Err(ChatError::NotFound),
+			Err(e) => {
+				println!("Error loading message: {}", e);
+				Err(ChatError::Unavailable)
+			}
+		}
+	}
+
+	async fn find_user(
+		broadcaster: &Addr<Broadcaster>,
+		nick: &str,
+	) -> Result<chat::Peer, ChatError> {
+		let find = chat::FindUser {
+			nick: String::from(nick),
+		};
+		ask(broadcaster, find).await?.ok_or(ChatError::NotFound)
+	}
+
+	/// Makes sure the message `msg_id` exists, and that the user `id` either wrote
+	/// it or is a moderator. Returns the user.
+	async fn check_message_access(
+		conn: &PgConnection,
+		broadcaster: &Addr<Broadcaster>,
+		id: u64,
+		msg_id: i64,
+	) -> Result<chat::UserInfo, ChatError> {
+		let user_msg = find_message(conn, msg_id)?;
+
+		let user = connected_user(broadcaster, id).await?;
+		if user_msg.author_id != id as i64 && !user.is_moderator {
+			return Err(ChatError::Forbidden(String::from(
+				"Only the author or a moderator can change this message",
+			)));
+		}
+		Ok(user)
+	}
+
+	fn parse_nick_args(args: &str) -> Option<(&str, Option<&str>)> {
+		let mut args = args.split_whitespace();
+		let nick = args.next()?;
+		Some((nick, args.next()))
 	}
 
 	pub fn now() -> chrono::NaiveDateTime {
@@ -372,3 +1370,35 @@ mod handlers {
 		chrono::NaiveDateTime::from_timestamp(since_unix.as_secs() as i64, 0)
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parses_durations() {
+		assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
+		assert_eq!(parse_duration("10m"), Some(Duration::from_secs(600)));
+		assert_eq!(parse_duration("2h"), Some(Duration::from_secs(7200)));
+		assert_eq!(parse_duration("1d"), Some(Duration::from_secs(86400)));
+		assert_eq!(parse_duration("0s"), Some(Duration::from_secs(0)));
+	}
+
+	#[test]
+	fn defaults_to_minutes() {
+		assert_eq!(parse_duration("5"), Some(Duration::from_secs(300)));
+	}
+
+	#[test]
+	fn rejects_malformed_durations() {
+		for input in &["", "m", "10x", "1.5h", "-1m", "10 m", "1hm", "10M"] {
+			assert_eq!(parse_duration(input), None, "{}", input);
+		}
+	}
+
+	#[test]
+	fn rejects_overflowing_durations() {
+		assert_eq!(parse_duration("99999999999999999999s"), None);
+		assert_eq!(parse_duration(&format!("{}d", u64::MAX / 1000)), None);
+	}
+}
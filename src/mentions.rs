@@ -0,0 +1,85 @@
+use crate::models::{Mention, UserMsg};
+use diesel::prelude::*;
+
+/// Nicks mentioned with `@nick` in `msg`, without the `@` and trailing punctuation.
+pub fn parse(msg: &str) -> Vec<&str> {
+	msg.split_whitespace()
+		.filter_map(|word| word.strip_prefix('@'))
+		.map(|nick| nick.trim_end_matches(|c: char| c.is_ascii_punctuation()))
+		.filter(|nick| !nick.is_empty())
+		.collect()
+}
+
+/// Stores the mentions of `nicks` by the message `message_id` as unread.
+pub fn save(conn: &PgConnection, message_id: i64, nicks: &[String]) -> QueryResult<()> {
+	use crate::schema::mentions::dsl::mentions;
+
+	let new_mentions = nicks
+		.iter()
+		.map(|nick| Mention {
+			nick: nick.to_lowercase(),
+			message_id,
+		})
+		.collect::<Vec<Mention>>();
+	diesel::insert_into(mentions)
+		.values(&new_mentions)
+		.on_conflict_do_nothing()
+		.execute(conn)
+		.map(|_| ())
+}
+
+/// Messages in `room_name` mentioning `nick` that it hasn't read yet, oldest first.
+pub fn unread(conn: &PgConnection, nick: &str, room_name: &str) -> QueryResult<Vec<UserMsg>> {
+	use crate::schema::{mentions, messages};
+
+	mentions::table
+		.inner_join(messages::table)
+		.select(messages::all_columns)
+		.filter(mentions::nick.eq(nick.to_lowercase()))
+		.filter(messages::room.eq(room_name))
+		.filter(messages::deleted.eq(false))
+		.order(messages::id.asc())
+		.load::<UserMsg>(conn)
+}
+
+/// Marks every mention of `nick` as read.
+pub fn mark_read(conn: &PgConnection, nick: &str) -> QueryResult<()> {
+	use crate::schema::mentions::dsl;
+
+	diesel::delete(dsl::mentions.filter(dsl::nick.eq(nick.to_lowercase())))
+		.execute(conn)
+		.map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn finds_mentions() {
+		assert_eq!(parse("@alice hi @Bob"), ["alice", "Bob"]);
+	}
+
+	#[test]
+	fn strips_trailing_punctuation() {
+		assert_eq!(
+			parse("thanks @alice, @bob! @carol?!"),
+			["alice", "bob", "carol"]
+		);
+		assert_eq!(parse("(@alice)"), Vec::<&str>::new());
+		assert_eq!(parse("@al.ice."), ["al.ice"]);
+	}
+
+	#[test]
+	fn ignores_lone_at_signs_and_addresses() {
+		assert_eq!(
+			parse("@ @! mail me at alice@example.com"),
+			Vec::<&str>::new()
+		);
+	}
+
+	#[test]
+	fn keeps_non_ascii_nicks() {
+		assert_eq!(parse("@Zoë: hello"), ["Zoë"]);
+	}
+}
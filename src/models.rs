@@ -1,30 +1,106 @@
-use crate::schema::{images, pastes};
+use crate::schema::{
+	bans, images, mentions, messages, pastes, presences, reactions, reserved_nicks,
+};
+use std::collections::BTreeMap;
 
-use chrono::NaiveDateTime;
+use chrono::{DateTime, NaiveDateTime, Utc};
 use diesel::{
 	dsl::Eq, insertable::Insertable, pg::Pg, prelude::*, query_builder::UndecoratedInsertRecord,
 	sql_types::*, Queryable,
 };
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, Serializer};
 
 #[derive(Serialize, Debug)]
 pub struct Paste {
+	/// Kept internal, since ids are sequential. Pastes are looked up by `slug`.
+	#[serde(skip)]
 	pub id: i64,
+	/// Random identifier of the paste in URLs
+	pub slug: String,
 	pub filename: Option<String>,
 	pub content: Option<String>,
 	pub creation_date: NaiveDateTime,
+	/// The paste can't be viewed anymore after that
+	pub expires_at: Option<NaiveDateTime>,
+	/// How many times the paste can be viewed before it's gone
+	pub max_views: Option<i32>,
+	pub views: i32,
+	/// Salted hash of the password needed to view the paste. Clients only
+	/// learn whether there is one.
+	#[serde(rename = "locked", serialize_with = "serialize_is_some")]
+	pub password_hash: Option<String>,
+}
+
+/// A paste as listed, with the content it shows highlighted by the server
+#[derive(Serialize, Debug)]
+pub struct ListedPaste {
+	#[serde(flatten)]
+	pub paste: Paste,
+	/// HTML with inline styles
+	pub rendered: Option<String>,
+}
+
+fn serialize_is_some<S: Serializer>(
+	value: &Option<String>,
+	serializer: S,
+) -> Result<S::Ok, S::Error> {
+	serializer.serialize_bool(value.is_some())
+}
+
+impl Paste {
+	/// Whether the paste expired or used up its views.
+	pub fn is_gone(&self, now: NaiveDateTime) -> bool {
+		self.expires_at.is_some_and(|expires_at| expires_at <= now)
+			|| self
+				.max_views
+				.is_some_and(|max_views| self.views >= max_views)
+	}
+
+	/// The paste as shown in the paste list and the chat. The content of locked
+	/// pastes and pastes with a view limit is only shown to whoever opens them.
+	pub fn listed(mut self) -> Self {
+		if self.max_views.is_some() || self.password_hash.is_some() {
+			self.content = None;
+		}
+		self
+	}
 }
 
 impl Queryable<pastes::SqlType, Pg> for Paste {
-	type Row = (i64, Option<String>, Option<String>, NaiveDateTime);
+	type Row = (
+		i64,
+		Option<String>,
+		Option<String>,
+		NaiveDateTime,
+		Option<NaiveDateTime>,
+		Option<i32>,
+		i32,
+		Option<String>,
+		String,
+	);
 
 	fn build(row: Self::Row) -> Self {
-		let (id, filename, content, creation_date) = row;
+		let (
+			id,
+			filename,
+			content,
+			creation_date,
+			expires_at,
+			max_views,
+			views,
+			password_hash,
+			slug,
+		) = row;
 		Paste {
 			id,
+			slug,
 			filename,
 			content,
 			creation_date,
+			expires_at,
+			max_views,
+			views,
+			password_hash,
 		}
 	}
 }
@@ -36,9 +112,14 @@ impl Queryable<(BigInt, Nullable<Text>, Timestamp), Pg> for Paste {
 		let (id, filename, creation_date) = row;
 		Paste {
 			id,
+			slug: String::new(),
 			filename,
 			content: None,
 			creation_date,
+			expires_at: None,
+			max_views: None,
+			views: 0,
+			password_hash: None,
 		}
 	}
 }
@@ -48,6 +129,10 @@ impl<'a> Insertable<pastes::table> for Paste {
 		Option<Eq<pastes::filename, String>>,
 		Option<Eq<pastes::content, String>>,
 		Option<Eq<pastes::creation_date, NaiveDateTime>>,
+		Option<Eq<pastes::expires_at, NaiveDateTime>>,
+		Option<Eq<pastes::max_views, i32>>,
+		Option<Eq<pastes::password_hash, String>>,
+		Option<Eq<pastes::slug, String>>,
 	) as Insertable<pastes::table>>::Values;
 
 	fn values(self) -> Self::Values {
@@ -55,6 +140,10 @@ impl<'a> Insertable<pastes::table> for Paste {
 			self.filename.map(|x| pastes::filename.eq(x)),
 			self.content.map(|x| pastes::content.eq(x)),
 			Some(pastes::creation_date.eq(self.creation_date)),
+			self.expires_at.map(|x| pastes::expires_at.eq(x)),
+			self.max_views.map(|x| pastes::max_views.eq(x)),
+			self.password_hash.map(|x| pastes::password_hash.eq(x)),
+			Some(pastes::slug.eq(self.slug)),
 		)
 			.values()
 	}
@@ -65,6 +154,10 @@ impl<'a> Insertable<pastes::table> for &'a Paste {
 		Option<Eq<pastes::filename, &'a String>>,
 		Option<Eq<pastes::content, &'a String>>,
 		Option<Eq<pastes::creation_date, &'a NaiveDateTime>>,
+		Option<Eq<pastes::expires_at, &'a NaiveDateTime>>,
+		Option<Eq<pastes::max_views, &'a i32>>,
+		Option<Eq<pastes::password_hash, &'a String>>,
+		Option<Eq<pastes::slug, &'a String>>,
 	) as Insertable<pastes::table>>::Values;
 
 	fn values(self) -> Self::Values {
@@ -72,6 +165,12 @@ impl<'a> Insertable<pastes::table> for &'a Paste {
 			self.filename.as_ref().map(|x| pastes::filename.eq(x)),
 			self.content.as_ref().map(|x| pastes::content.eq(x)),
 			Some(pastes::creation_date.eq(&self.creation_date)),
+			self.expires_at.as_ref().map(|x| pastes::expires_at.eq(x)),
+			self.max_views.as_ref().map(|x| pastes::max_views.eq(x)),
+			self.password_hash
+				.as_ref()
+				.map(|x| pastes::password_hash.eq(x)),
+			Some(pastes::slug.eq(&self.slug)),
 		)
 			.values()
 	}
@@ -128,3 +227,94 @@ pub struct NewImage {
 	pub creation_date: NaiveDateTime,
 	pub content: Vec<u8>,
 }
+
+#[derive(Queryable, Serialize, Debug)]
+pub struct UserMsg {
+	pub id: i64,
+	pub room: String,
+	pub nick: String,
+	pub custom_nick_color: Option<String>,
+	pub msg: String,
+	pub time: DateTime<Utc>,
+	/// Id of the user who sent the message, kept private to not link nicks together
+	#[serde(skip)]
+	pub author_id: i64,
+	pub edited_at: Option<DateTime<Utc>>,
+	#[serde(skip)]
+	pub deleted: bool,
+	/// Id of the first message of the thread this message replies to
+	pub reply_to: Option<i64>,
+}
+
+#[derive(Insertable, Debug)]
+#[table_name = "messages"]
+pub struct NewUserMsg {
+	pub room: String,
+	pub nick: String,
+	pub custom_nick_color: Option<String>,
+	pub msg: String,
+	pub time: DateTime<Utc>,
+	pub author_id: i64,
+	pub reply_to: Option<i64>,
+}
+
+/// Number of users who reacted with each emoji
+pub type Reactions = BTreeMap<String, i64>;
+
+/// Message from the room history, along with its reactions and replies count
+#[derive(Serialize, Debug)]
+pub struct HistoryEntry {
+	#[serde(flatten)]
+	pub msg: UserMsg,
+	pub reactions: Reactions,
+	pub replies: i64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Thread {
+	pub root: HistoryEntry,
+	pub replies: Vec<HistoryEntry>,
+}
+
+#[derive(Queryable, Insertable, Debug)]
+#[table_name = "reactions"]
+pub struct Reaction {
+	pub message_id: i64,
+	pub user_id: i64,
+	pub emoji: String,
+}
+
+/// Ban of a nick, an IP, or both. `nick` is stored lowercase.
+#[derive(Insertable, Debug)]
+#[table_name = "bans"]
+pub struct NewBan {
+	pub nick: Option<String>,
+	pub ip: Option<String>,
+	pub creation_date: NaiveDateTime,
+}
+
+/// Snapshot of an instance of a cluster, as JSON
+#[derive(Queryable, Insertable, AsChangeset, Debug)]
+#[table_name = "presences"]
+pub struct NodePresence {
+	pub node: i64,
+	pub snapshot: String,
+	pub updated_at: NaiveDateTime,
+}
+
+/// Mention of `nick` the user hasn't read yet. `nick` is stored lowercase.
+#[derive(Queryable, Insertable, Debug)]
+#[table_name = "mentions"]
+pub struct Mention {
+	pub nick: String,
+	pub message_id: i64,
+}
+
+/// Nick claimed with a secret. `nick` is stored lowercase.
+#[derive(Queryable, Insertable, Debug)]
+#[table_name = "reserved_nicks"]
+pub struct ReservedNick {
+	pub nick: String,
+	pub secret_hash: String,
+	pub creation_date: NaiveDateTime,
+}
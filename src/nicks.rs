@@ -0,0 +1,60 @@
+use crate::error::ChatError;
+use crate::models::ReservedNick;
+use crate::secret;
+use diesel::prelude::*;
+
+/// Makes sure `nick` is either free or claimed with `secret`.
+pub fn check_reservation(
+	conn: &PgConnection,
+	nick: &str,
+	secret: Option<&str>,
+) -> Result<(), ChatError> {
+	let reservation = {
+		use crate::schema::reserved_nicks::dsl;
+
+		dsl::reserved_nicks
+			.find(nick.to_lowercase())
+			.first::<ReservedNick>(conn)
+			.optional()
+			.map_err(|e| {
+				println!("Error loading nick reservation: {}", e);
+				ChatError::Unavailable
+			})?
+	};
+
+	match (reservation, secret) {
+		(None, _) => Ok(()),
+		(Some(reservation), Some(secret)) if secret::verify(secret, &reservation.secret_hash) => {
+			Ok(())
+		}
+		(Some(_), _) => Err(ChatError::Forbidden(String::from(
+			"This nick is reserved, a valid secret is required",
+		))),
+	}
+}
+
+/// Claims `nick`, so that only clients knowing `secret` can use it from now on.
+pub fn reserve(conn: &PgConnection, nick: &str, secret: &str) -> Result<(), ChatError> {
+	use crate::schema::reserved_nicks::dsl::reserved_nicks;
+
+	let reservation = ReservedNick {
+		nick: nick.to_lowercase(),
+		secret_hash: secret::hash(secret),
+		creation_date: crate::handlers::now(),
+	};
+	let inserted = diesel::insert_into(reserved_nicks)
+		.values(&reservation)
+		.on_conflict_do_nothing()
+		.execute(conn)
+		.map_err(|e| {
+			println!("Error reserving nick: {}", e);
+			ChatError::Unavailable
+		})?;
+
+	match inserted {
+		0 => Err(ChatError::Conflict(String::from(
+			"This nick is already reserved",
+		))),
+		_ => Ok(()),
+	}
+}
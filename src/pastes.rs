@@ -0,0 +1,122 @@
+use std::thread;
+use std::time::Duration;
+
+use crate::error::ChatError;
+use crate::handlers::now;
+use crate::models::Paste;
+use crate::{secret, Pool};
+use diesel::prelude::*;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+
+/// How often expired and used up pastes are purged
+const REAP_INTERVAL: Duration = Duration::from_secs(60);
+/// How many slugs are tried before giving up on saving a paste
+const MAX_SLUG_ATTEMPTS: usize = 5;
+
+/// Saves `paste` under a random base62 slug of `slug_len` characters, picking
+/// another one if it's already taken.
+pub fn insert(conn: &PgConnection, mut paste: Paste, slug_len: usize) -> Result<Paste, ChatError> {
+	use crate::schema::pastes::dsl::pastes;
+
+	for _ in 0..MAX_SLUG_ATTEMPTS {
+		paste.slug = rand::thread_rng()
+			.sample_iter(&Alphanumeric)
+			.take(slug_len)
+			.collect();
+		let inserted = diesel::insert_into(pastes)
+			.values(&paste)
+			.on_conflict_do_nothing()
+			.get_result::<Paste>(conn)
+			.optional()
+			.map_err(|e| {
+				println!("Error inserting new paste: {}", e);
+				ChatError::Unavailable
+			})?;
+		if let Some(paste) = inserted {
+			return Ok(paste);
+		}
+	}
+
+	println!("Failed to find a free paste slug, consider a longer paste_slug_length");
+	Err(ChatError::Internal)
+}
+
+/// Counts a view of the paste `paste_slug` and returns it, if `password` unlocks
+/// it. The content of a paste is wiped as soon as its last view is used up, rather
+/// than when it's reaped.
+pub fn view(
+	conn: &PgConnection,
+	paste_slug: &str,
+	password: Option<&str>,
+) -> Result<Paste, ChatError> {
+	use crate::schema::pastes::dsl::*;
+
+	let viewed = conn.transaction(|| {
+		let paste = match pastes
+			.filter(slug.eq(paste_slug))
+			.for_update()
+			.first::<Paste>(conn)
+			.optional()?
+		{
+			Some(paste) => paste,
+			None => return Ok(Err(ChatError::NotFound)),
+		};
+		if paste.is_gone(now()) {
+			return Ok(Err(ChatError::Gone));
+		}
+		if let Some(hash) = &paste.password_hash {
+			match password {
+				Some(password) if secret::verify(password, hash) => (),
+				Some(_) => return Ok(Err(ChatError::Forbidden(String::from("Wrong password")))),
+				None => return Ok(Err(ChatError::Unauthorized)),
+			}
+		}
+
+		let burnt = paste.max_views == Some(paste.views + 1);
+		diesel::update(pastes.find(paste.id))
+			.set(views.eq(views + 1))
+			.execute(conn)?;
+		if burnt {
+			diesel::update(pastes.find(paste.id))
+				.set(content.eq(None::<String>))
+				.execute(conn)?;
+		}
+		Ok(Ok(paste))
+	});
+
+	viewed.unwrap_or_else(|e: diesel::result::Error| {
+		println!("Error viewing paste: {}", e);
+		Err(ChatError::Unavailable)
+	})
+}
+
+pub fn start_reaper(pool: Pool) {
+	// On its own thread, as diesel blocks
+	thread::spawn(move || reaper(pool));
+}
+
+/// Deletes expired and used up pastes every `REAP_INTERVAL`.
+fn reaper(pool: Pool) {
+	use crate::schema::pastes::dsl::*;
+
+	loop {
+		thread::sleep(REAP_INTERVAL);
+
+		let db_conn = match pool.get() {
+			Ok(conn) => conn,
+			Err(e) => {
+				println!("Failed to get connection to the database: {}", e);
+				continue;
+			}
+		};
+		let reaped = diesel::delete(pastes.filter(expires_at.le(now())))
+			.execute(&db_conn)
+			.and_then(|_| {
+				diesel::delete(pastes.filter(views.nullable().ge(max_views))).execute(&db_conn)
+			});
+		if let Err(e) = reaped {
+			println!("Failed to purge pastes: {}", e);
+		}
+	}
+}
@@ -0,0 +1,54 @@
+use crate::cluster::Snapshot;
+use crate::models::NodePresence;
+use diesel::prelude::*;
+
+/// Snapshots not refreshed for this long belong to instances that stopped. A
+/// few heartbeats of the broadcaster.
+const EXPIRY_SECS: i64 = 30;
+
+/// Replaces the snapshot of `node`, and drops the ones of stopped instances.
+pub fn save(conn: &PgConnection, node: u64, snapshot: &Snapshot) -> QueryResult<()> {
+	use crate::schema::presences::dsl;
+
+	let now = crate::handlers::now();
+	let presence = NodePresence {
+		node: node as i64,
+		snapshot: serde_json::to_string(snapshot).unwrap(),
+		updated_at: now,
+	};
+	diesel::insert_into(dsl::presences)
+		.values(&presence)
+		.on_conflict(dsl::node)
+		.do_update()
+		.set(&presence)
+		.execute(conn)?;
+
+	diesel::delete(dsl::presences.filter(dsl::updated_at.lt(expiry(now)))).execute(conn)?;
+	Ok(())
+}
+
+/// Snapshots of every other running instance
+pub fn others(conn: &PgConnection, node: u64) -> QueryResult<Vec<Snapshot>> {
+	use crate::schema::presences::dsl;
+
+	let presences = dsl::presences
+		.filter(dsl::node.ne(node as i64))
+		.filter(dsl::updated_at.ge(expiry(crate::handlers::now())))
+		.load::<NodePresence>(conn)?;
+
+	let snapshots = presences
+		.into_iter()
+		.filter_map(|presence| match serde_json::from_str(&presence.snapshot) {
+			Ok(snapshot) => Some(snapshot),
+			Err(e) => {
+				println!("Invalid snapshot of another instance: {}", e);
+				None
+			}
+		})
+		.collect();
+	Ok(snapshots)
+}
+
+fn expiry(now: chrono::NaiveDateTime) -> chrono::NaiveDateTime {
+	now - chrono::Duration::seconds(EXPIRY_SECS)
+}
@@ -1,3 +1,12 @@
+table! {
+	bans (id) {
+		id -> Int8,
+		nick -> Nullable<Varchar>,
+		ip -> Nullable<Varchar>,
+		creation_date -> Timestamp,
+	}
+}
+
 table! {
 	images (id) {
 		id -> Int8,
@@ -7,13 +16,76 @@ table! {
 	}
 }
 
+table! {
+	messages (id) {
+		id -> Int8,
+		room -> Varchar,
+		nick -> Varchar,
+		custom_nick_color -> Nullable<Varchar>,
+		msg -> Text,
+		time -> Timestamptz,
+		author_id -> Int8,
+		edited_at -> Nullable<Timestamptz>,
+		deleted -> Bool,
+		reply_to -> Nullable<Int8>,
+	}
+}
+
+table! {
+	mentions (nick, message_id) {
+		nick -> Varchar,
+		message_id -> Int8,
+	}
+}
+
 table! {
 	pastes (id) {
 		id -> Int8,
 		filename -> Nullable<Varchar>,
 		content -> Nullable<Text>,
 		creation_date -> Timestamp,
+		expires_at -> Nullable<Timestamp>,
+		max_views -> Nullable<Int4>,
+		views -> Int4,
+		password_hash -> Nullable<Text>,
+		slug -> Varchar,
 	}
 }
 
-allow_tables_to_appear_in_same_query!(images, pastes,);
+table! {
+	presences (node) {
+		node -> Int8,
+		snapshot -> Text,
+		updated_at -> Timestamp,
+	}
+}
+
+table! {
+	reactions (message_id, user_id, emoji) {
+		message_id -> Int8,
+		user_id -> Int8,
+		emoji -> Varchar,
+	}
+}
+
+table! {
+	reserved_nicks (nick) {
+		nick -> Varchar,
+		secret_hash -> Varchar,
+		creation_date -> Timestamp,
+	}
+}
+
+joinable!(mentions -> messages (message_id));
+joinable!(reactions -> messages (message_id));
+
+allow_tables_to_appear_in_same_query!(
+	bans,
+	images,
+	mentions,
+	messages,
+	pastes,
+	presences,
+	reactions,
+	reserved_nicks,
+);
@@ -0,0 +1,22 @@
+/// PBKDF2 iterations used for stored secrets
+const ROUNDS: u32 = 10_000;
+
+/// Salts and hashes `secret` for storage.
+pub fn hash(secret: &str) -> String {
+	pbkdf2::pbkdf2_simple(secret, ROUNDS).expect("Failed to generate salt")
+}
+
+/// Checks `secret` against a hash produced by [`hash`].
+pub fn verify(secret: &str, hash: &str) -> bool {
+	pbkdf2::pbkdf2_check(secret, hash).is_ok()
+}
+
+/// Compares secrets kept in plain text, in a time that doesn't depend on where
+/// they differ.
+pub fn eq(a: &str, b: &str) -> bool {
+	a.len() == b.len()
+		&& a.bytes()
+			.zip(b.bytes())
+			.fold(0, |diff, (a, b)| diff | (a ^ b))
+			== 0
+}
@@ -0,0 +1,137 @@
+use crate::chat::{self, Broadcaster, Event};
+use crate::error::ChatError;
+use crate::handlers::{self, ChatCommand, NewMsg, TypingNotice};
+use crate::limiter::Limiters;
+use crate::{Config, Pool};
+use actix::fut::{ActorFuture, WrapFuture};
+use actix::{Actor, ActorContext, Addr, AsyncContext, StreamHandler};
+use actix_web::web::Data;
+use actix_web_actors::ws;
+use serde::Deserialize;
+use tokio::sync::mpsc;
+
+/// Frames a client can send over the socket, mirroring `/send_msg`, `/send_cmd`
+/// and `/typing`
+#[derive(Deserialize)]
+enum WsRequest {
+	Msg(NewMsg),
+	Cmd(ChatCommand),
+	Typing(TypingNotice),
+}
+
+/// Full-duplex alternative to the `/events` stream. Carries the same events as the
+/// server-sent events stream, and accepts messages and commands from the client.
+pub struct WsSession {
+	id: u64,
+	rx: Option<mpsc::Receiver<Event>>,
+	broadcaster: Addr<Broadcaster>,
+	pool: Data<Pool>,
+	config: Data<Config>,
+	limiters: Data<Limiters>,
+}
+
+impl WsSession {
+	pub fn new(
+		id: u64,
+		rx: mpsc::Receiver<Event>,
+		broadcaster: Addr<Broadcaster>,
+		pool: Data<Pool>,
+		config: Data<Config>,
+		limiters: Data<Limiters>,
+	) -> Self {
+		WsSession {
+			id,
+			rx: Some(rx),
+			broadcaster,
+			pool,
+			config,
+			limiters,
+		}
+	}
+
+	/// Handles a frame from the client. Frames are handled one at a time, so that
+	/// messages are sent in the order the client sent them. Errors are sent back as
+	/// `Error` events.
+	fn handle_request(&self, text: &str, ctx: &mut ws::WebsocketContext<Self>) {
+		let request = match serde_json::from_str::<WsRequest>(text) {
+			Ok(request) => request,
+			Err(e) => {
+				send_error(ctx, ChatError::BadRequest(e.to_string()));
+				return;
+			}
+		};
+
+		let id = self.id;
+		let broadcaster = self.broadcaster.clone();
+		let pool = self.pool.clone();
+		let config = self.config.clone();
+		let limiters = self.limiters.clone();
+		let handled = async move {
+			match request {
+				WsRequest::Msg(msg) => {
+					handlers::post_msg(id, msg, &broadcaster, &pool, &limiters).await
+				}
+				WsRequest::Cmd(cmd) => {
+					handlers::run_command(id, cmd, &broadcaster, &pool, &config, &limiters).await
+				}
+				WsRequest::Typing(notice) => {
+					handlers::notify_typing(id, notice, &broadcaster).await
+				}
+			}
+		};
+		ctx.wait(handled.into_actor(self).map(|result, _, ctx| {
+			if let Err(e) = result {
+				send_error(ctx, e);
+			}
+		}));
+	}
+}
+
+fn send_error(ctx: &mut ws::WebsocketContext<WsSession>, error: ChatError) {
+	let error = chat::event_data(chat::Msg::error_msg(&error.body()));
+	ctx.text(String::from_utf8_lossy(&error.data));
+}
+
+impl Actor for WsSession {
+	type Context = ws::WebsocketContext<Self>;
+
+	fn started(&mut self, ctx: &mut Self::Context) {
+		if let Some(rx) = self.rx.take() {
+			ctx.add_stream(rx);
+		}
+	}
+}
+
+/// Events from the broadcaster
+impl StreamHandler<Event> for WsSession {
+	fn handle(&mut self, event: Event, ctx: &mut Self::Context) {
+		ctx.text(String::from_utf8_lossy(&event.data));
+	}
+
+	fn finished(&mut self, ctx: &mut Self::Context) {
+		ctx.close(None);
+		ctx.stop();
+	}
+}
+
+/// Frames from the client
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsSession {
+	fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+		let msg = match msg {
+			Ok(msg) => msg,
+			Err(_) => {
+				ctx.stop();
+				return;
+			}
+		};
+		match msg {
+			ws::Message::Text(text) => self.handle_request(&text, ctx),
+			ws::Message::Ping(msg) => ctx.pong(&msg),
+			ws::Message::Close(reason) => {
+				ctx.close(reason);
+				ctx.stop();
+			}
+			_ => (),
+		}
+	}
+}